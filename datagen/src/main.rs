@@ -105,7 +105,7 @@ fn play_game(
 ) -> Game {
     let (mut game, mut board) = pick_startpos();
 
-    let mut limits = [Limits::default(); 2];
+    let mut limits = [Limits::default(), Limits::default()];
     limits[0].min_nodes = Some(a_nodes);
     limits[0].nodes = Some(100 * a_nodes);
     limits[0].quantize_eval = a_weaken + 1;
@@ -133,9 +133,10 @@ fn play_game(
                     result = Some((info.pv[0], info.score));
                 }
             },
+            curr_move: &mut |_, _| {},
             data: &mut local[idx],
             shared: &mut shared[idx],
-            limits: limits[idx],
+            limits: limits[idx].clone(),
         }
         .search();
 
@@ -0,0 +1,410 @@
+use std::io::prelude::Read;
+use std::io::stdin;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use cozy_chess::util::{display_san_move, parse_san_move};
+use cozy_chess::{Board, Color, GameStatus, Move};
+use frostburn::{Eval, Limits, LocalData, SharedData};
+
+use crate::bench::search_threads;
+
+const INACCURACY: u16 = 50;
+const MISTAKE: u16 = 100;
+const BLUNDER: u16 = 150;
+
+/// Plies of the engine's principal variation shown for a bad move.
+const VARIATION_PLIES: usize = 8;
+
+struct Game {
+    tags: Vec<(String, String)>,
+    movetext: Vec<String>,
+    result: Option<String>,
+}
+
+impl Game {
+    fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn start_position(&self) -> Result<Board, String> {
+        let Some(fen) = self.tag("FEN") else {
+            return match self.tag("Variant") {
+                Some(v) if is_chess960(v) => Err("Chess960 game without a FEN tag".to_owned()),
+                _ => Ok(Board::startpos()),
+            };
+        };
+        fen.parse()
+            .map_err(|e| format!("invalid FEN tag `{fen}`: {e}"))
+    }
+}
+
+fn is_chess960(variant: &str) -> bool {
+    let variant = variant.to_ascii_lowercase();
+    variant.contains("960") || variant.contains("fischerandom")
+}
+
+fn is_result(token: &str) -> bool {
+    matches!(token, "1-0" | "0-1" | "1/2-1/2" | "*")
+}
+
+fn parse_tag(line: &str) -> Option<(String, String)> {
+    let inner = line.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (name, value) = inner.split_once(char::is_whitespace)?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((
+        name.to_owned(),
+        value.replace("\\\"", "\"").replace("\\\\", "\\"),
+    ))
+}
+
+/// Splits PGN text into games, keeping only the tags and the moves of the main line. Comments,
+/// variations and NAGs are dropped since the annotations replace them.
+fn parse_games(text: &str) -> Vec<Game> {
+    let mut games = vec![];
+    let mut game = Game {
+        tags: vec![],
+        movetext: vec![],
+        result: None,
+    };
+    let mut in_comment = false;
+    let mut depth = 0usize;
+
+    for line in text.lines() {
+        if !in_comment && depth == 0 && line.starts_with('[') {
+            if !game.movetext.is_empty() {
+                games.push(std::mem::replace(
+                    &mut game,
+                    Game {
+                        tags: vec![],
+                        movetext: vec![],
+                        result: None,
+                    },
+                ));
+            }
+            game.tags.extend(parse_tag(line));
+            continue;
+        }
+        if !in_comment && line.starts_with('%') {
+            continue;
+        }
+
+        let mut token = String::new();
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            let delimiter = in_comment || c.is_whitespace() || "{}();$".contains(c);
+            if delimiter && !token.is_empty() {
+                if depth == 0 {
+                    game.movetext.push(std::mem::take(&mut token));
+                }
+                token.clear();
+            }
+            match c {
+                _ if in_comment => in_comment = c != '}',
+                '{' => in_comment = true,
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                ';' => break,
+                '$' => while chars.next_if(char::is_ascii_digit).is_some() {},
+                _ if c.is_whitespace() => {}
+                _ => token.push(c),
+            }
+        }
+        if !token.is_empty() && depth == 0 {
+            game.movetext.push(token);
+        }
+
+        if let Some(last) = game.movetext.last().filter(|t| is_result(t)) {
+            game.result = Some(last.clone());
+            game.movetext.pop();
+            games.push(std::mem::replace(
+                &mut game,
+                Game {
+                    tags: vec![],
+                    movetext: vec![],
+                    result: None,
+                },
+            ));
+        }
+    }
+    if !game.movetext.is_empty() || !game.tags.is_empty() {
+        games.push(game);
+    }
+    games
+}
+
+/// Parses the main line of `game`, skipping move numbers and move suffix annotations.
+fn parse_moves(board: &Board, game: &Game) -> Result<Vec<Move>, String> {
+    let mut board = board.clone();
+    let mut moves = vec![];
+    for token in &game.movetext {
+        let san = token
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .trim_start_matches('.')
+            .trim_end_matches(['!', '?']);
+        if san.is_empty() || is_result(san) {
+            continue;
+        }
+        let mv = parse_san_move(&board, san).map_err(|_| {
+            format!(
+                "illegal move `{token}` after {} moves",
+                moves.len().div_ceil(2)
+            )
+        })?;
+        moves.push(mv);
+        board.play_unchecked(mv);
+    }
+    Ok(moves)
+}
+
+struct Analysis {
+    score: Eval,
+    pv: Vec<Move>,
+}
+
+/// Expected score of the side to move in permille.
+fn expected_score(score: Eval, board: &Board) -> u16 {
+    let (win, draw, _) = score.wdl(board);
+    win + draw / 2
+}
+
+/// `[%eval]` value of `score` from white's perspective.
+fn format_eval(score: Eval, stm: Color) -> String {
+    let score = match stm {
+        Color::White => score,
+        Color::Black => -score,
+    };
+    match (score.mating_in(), (-score).mating_in()) {
+        (Some(n), _) => format!("#{n}"),
+        (_, Some(n)) => format!("#-{n}"),
+        _ => format!("{:.2}", (score - Eval::cp(0)) as f64 / 100.0),
+    }
+}
+
+fn move_number(board: &Board, force: bool) -> Option<String> {
+    match board.side_to_move() {
+        Color::White => Some(format!("{}.", board.fullmove_number())),
+        Color::Black if force => Some(format!("{}...", board.fullmove_number())),
+        Color::Black => None,
+    }
+}
+
+fn variation(board: &Board, analysis: &Analysis) -> String {
+    let mut board = board.clone();
+    let mut tokens = vec!["(".to_owned()];
+    for (i, &mv) in analysis.pv.iter().take(VARIATION_PLIES).enumerate() {
+        tokens.extend(move_number(&board, i == 0));
+        tokens.push(display_san_move(&board, mv).to_string());
+        if i == 0 {
+            tokens.push(format!(
+                "{{[%eval {}]}}",
+                format_eval(analysis.score, board.side_to_move())
+            ));
+        }
+        board.play_unchecked(mv);
+    }
+    tokens.push(")".to_owned());
+    tokens.join(" ").replace("( ", "(").replace(" )", ")")
+}
+
+fn write_game(game: &Game, start: &Board, moves: &[Move], analyses: &[Analysis]) {
+    for (name, value) in &game.tags {
+        if name != "Annotator" {
+            println!(
+                "[{name} \"{}\"]",
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            );
+        }
+    }
+    println!("[Annotator \"Frostburn\"]");
+    println!();
+
+    let mut tokens = vec![];
+    let mut board = start.clone();
+    for (i, &mv) in moves.iter().enumerate() {
+        let (before, after) = (&analyses[i], &analyses[i + 1]);
+        let mut next = board.clone();
+        next.play_unchecked(mv);
+
+        // the expected score after the move is that of the opponent, so it is mirrored
+        let expected_before = expected_score(before.score, &board);
+        let expected_after = 1000 - expected_score(after.score, &next);
+        let loss = expected_before.saturating_sub(expected_after);
+        let nag = match loss {
+            _ if before.pv.first() == Some(&mv) => "",
+            l if l >= BLUNDER => "??",
+            l if l >= MISTAKE => "?",
+            l if l >= INACCURACY => "?!",
+            _ => "",
+        };
+
+        tokens.extend(move_number(&board, true));
+        tokens.push(format!("{}{nag}", display_san_move(&board, mv)));
+        // checkmate speaks for itself
+        if next.status() != GameStatus::Won {
+            tokens.push(format!(
+                "{{[%eval {}]}}",
+                format_eval(after.score, next.side_to_move())
+            ));
+        }
+        if !nag.is_empty() && !before.pv.is_empty() {
+            tokens.push(variation(&board, before));
+        }
+        board = next;
+    }
+    tokens.push(
+        game.result
+            .clone()
+            .or_else(|| game.tag("Result").map(str::to_owned))
+            .unwrap_or_else(|| "*".to_owned()),
+    );
+
+    let mut line = String::new();
+    for token in tokens {
+        if !line.is_empty() && line.len() + token.len() >= 80 {
+            println!("{line}");
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line += &token;
+    }
+    println!("{line}");
+    println!();
+}
+
+/// `annotate [options] [file]`: searches every position of the games in a PGN file (or stdin) and
+/// writes the games back with evaluations, better moves and `?!`, `?` and `??` for bad moves.
+pub fn run(mut args: impl Iterator<Item = String>) {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: annotate [--time MS] [--depth N] [--nodes N] [--hash MB] [--threads N] [file]"
+        );
+        eprintln!("the pgn is read from stdin unless a file is given");
+        eprintln!("the search is limited to 1000ms per position unless a limit is given");
+        exit(1);
+    };
+
+    let mut limits = Limits::default();
+    let mut hash_mb = 64;
+    let mut threads = 1;
+    let mut file = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match &*arg {
+            "--time" => {
+                let ms = value().parse().unwrap_or_else(|_| usage());
+                limits.move_time = Some(Duration::from_millis(ms));
+            }
+            "--depth" => limits.depth = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--nodes" => limits.nodes = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--hash" => hash_mb = value().parse().unwrap_or_else(|_| usage()),
+            "--threads" => threads = value().parse().unwrap_or_else(|_| usage()),
+            _ if arg.starts_with("--") || file.is_some() => usage(),
+            _ => file = Some(arg),
+        }
+    }
+    if threads == 0 {
+        usage();
+    }
+    if limits.move_time.is_none() && limits.depth.is_none() && limits.nodes.is_none() {
+        limits.move_time = Some(Duration::from_millis(1000));
+    }
+
+    let text = match &file {
+        Some(path) => std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("failed to read {path}: {e}");
+            exit(1);
+        }),
+        None => {
+            let mut text = String::new();
+            stdin().read_to_string(&mut text).unwrap_or_else(|e| {
+                eprintln!("failed to read stdin: {e}");
+                exit(1);
+            });
+            text
+        }
+    };
+
+    let mut shared = SharedData::new(hash_mb);
+    let mut locals: Vec<_> = (0..threads).map(|_| LocalData::new()).collect();
+    let games = parse_games(&text);
+    for (number, game) in games.iter().enumerate() {
+        let parsed = game
+            .start_position()
+            .and_then(|start| Ok((parse_moves(&start, game)?, start)));
+        let (moves, start) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("skipping game {}: {e}", number + 1);
+                continue;
+            }
+        };
+
+        for block in shared.get_clear_tt_blocks(1) {
+            shared.clear_tt_block(block);
+        }
+        for local in &mut locals {
+            *local = LocalData::new();
+        }
+
+        let game_start = Instant::now();
+        let mut board = start.clone();
+        let mut history = vec![];
+        let mut analyses = vec![];
+        for i in 0..=moves.len() {
+            let analysis = match board.status() {
+                GameStatus::Won => Analysis {
+                    score: Eval::mated(0),
+                    pv: vec![],
+                },
+                GameStatus::Drawn => Analysis {
+                    score: Eval::cp(0),
+                    pv: vec![],
+                },
+                GameStatus::Ongoing => {
+                    shared.prepare_for_search();
+                    let mut analysis = Analysis {
+                        score: Eval::cp(0),
+                        pv: vec![],
+                    };
+                    let start = Instant::now();
+                    search_threads(
+                        &shared,
+                        &mut locals,
+                        &board,
+                        &history,
+                        limits.clone(),
+                        &|| start.elapsed(),
+                        &mut |info| {
+                            if info.multi_pv == 1 {
+                                analysis.score = info.score;
+                                analysis.pv = info.pv.to_vec();
+                            }
+                        },
+                    );
+                    analysis
+                }
+            };
+            analyses.push(analysis);
+
+            if let Some(&mv) = moves.get(i) {
+                history.push(board.hash());
+                board.play_unchecked(mv);
+            }
+        }
+
+        write_game(game, &start, &moves, &analyses);
+        eprintln!(
+            "annotated game {}/{} ({} plies) in {:.1}s",
+            number + 1,
+            games.len(),
+            moves.len(),
+            game_start.elapsed().as_secs_f64()
+        );
+    }
+}
@@ -1,44 +1,235 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process::exit;
 use std::time::{Duration, Instant};
 
-use frostburn::{Limits, LocalData, Search, SharedData};
+use cozy_chess::util::display_uci_move;
+use cozy_chess::Board;
+use frostburn::{Limits, LocalData, NnueBackend, Search, SearchInfo, SharedData};
 
-pub fn bench() {
-    let mut shared = SharedData::new(64);
+struct BenchConfig {
+    depth: i16,
+    hash_mb: usize,
+    threads: usize,
+    backend: NnueBackend,
+    file: Option<String>,
+    expected: Option<u64>,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig {
+            depth: 15,
+            hash_mb: 64,
+            threads: 1,
+            backend: NnueBackend::default(),
+            file: None,
+            expected: None,
+        }
+    }
+}
+
+/// `bench [options]`: searches a fixed set of positions and reports the total node count. With
+/// the default options this is the signature recorded in commit messages.
+pub fn run(mut args: impl Iterator<Item = String>) {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: bench [--depth N] [--hash MB] [--threads N] [--backend NAME] [--file PATH] \
+             [--expect NODES]"
+        );
+        eprintln!("--file reads one fen or epd position per line");
+        eprintln!("--expect fails unless the total node count matches, which requires 1 thread");
+        exit(1);
+    };
+
+    let mut config = BenchConfig::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match &*arg {
+            "--depth" => config.depth = value().parse().unwrap_or_else(|_| usage()),
+            "--hash" => config.hash_mb = value().parse().unwrap_or_else(|_| usage()),
+            "--threads" => config.threads = value().parse().unwrap_or_else(|_| usage()),
+            "--backend" => {
+                let name = value();
+                config.backend = NnueBackend::available()
+                    .into_iter()
+                    .find(|backend| backend.name() == name)
+                    .unwrap_or_else(|| {
+                        let available: Vec<_> =
+                            NnueBackend::available().iter().map(|b| b.name()).collect();
+                        eprintln!(
+                            "unavailable NNUE backend `{name}`, expected one of {}",
+                            available.join(", ")
+                        );
+                        exit(1);
+                    });
+            }
+            "--file" => config.file = Some(value()),
+            "--expect" => config.expected = Some(value().parse().unwrap_or_else(|_| usage())),
+            _ => usage(),
+        }
+    }
+    if !(1..=120).contains(&config.depth) || config.threads == 0 {
+        usage();
+    }
+
+    let positions = match &config.file {
+        None => BENCH_FENS.iter().map(|fen| fen.parse().unwrap()).collect(),
+        Some(path) => read_positions(path),
+    };
+    bench(&config, &positions);
+}
+
+fn read_positions(path: &str) -> Vec<Board> {
+    let file = File::open(path).unwrap_or_else(|e| {
+        eprintln!("failed to open {path}: {e}");
+        exit(1);
+    });
+
+    let mut positions = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.unwrap_or_else(|e| {
+            eprintln!("failed to read {path}: {e}");
+            exit(1);
+        });
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_position(&line) {
+            Ok(board) => positions.push(board),
+            Err(e) => {
+                eprintln!("{path}:{}: {e}", i + 1);
+                exit(1);
+            }
+        }
+    }
+    positions
+}
+
+/// Parses a full FEN or an EPD record, whose position has no move counters and may be followed by
+/// operations.
+pub fn parse_position(line: &str) -> Result<Board, String> {
+    let fields: Vec<_> = line.split_ascii_whitespace().collect();
+    if fields.len() < 4 {
+        return Err(format!("invalid position `{line}`"));
+    }
+    let counters = match fields.get(4..6) {
+        Some([halfmove, fullmove])
+            if halfmove.parse::<u32>().is_ok() && fullmove.parse::<u32>().is_ok() =>
+        {
+            [*halfmove, *fullmove]
+        }
+        _ => ["0", "1"],
+    };
+    let fen = format!("{} {} {}", fields[..4].join(" "), counters[0], counters[1]);
+    fen.parse()
+        .map_err(|e| format!("invalid position `{fen}`: {e:?}"))
+}
+
+fn bench(config: &BenchConfig, positions: &[Board]) {
+    let mut shared = SharedData::new(config.hash_mb);
+    shared.nnue_backend = config.backend;
     for block in shared.get_clear_tt_blocks(1) {
         shared.clear_tt_block(block);
     }
-    let mut local = LocalData::new();
+    let mut locals: Vec<_> = (0..config.threads).map(|_| LocalData::new()).collect();
 
     let mut search_time = Duration::ZERO;
     let mut total_nodes = 0;
-    for fen in BENCH_FENS {
-        let root = fen.parse().unwrap();
-
+    for (i, root) in positions.iter().enumerate() {
         shared.prepare_for_search();
         let mut nodes = 0;
+        let mut best_move = None;
         let t = Instant::now();
-        Search {
-            root: &root,
-            history: vec![],
-            clock: &|| Duration::ZERO,
-            info: &mut |info| nodes = info.nodes,
-            data: &mut local,
-            shared: &shared,
-            limits: Limits {
-                depth: Some(15),
-                ..Default::default()
+        let limits = Limits {
+            depth: Some(config.depth),
+            ..Default::default()
+        };
+        search_threads(
+            &shared,
+            &mut locals,
+            root,
+            &[],
+            limits,
+            &|| Duration::ZERO,
+            &mut |info| {
+                nodes = info.nodes;
+                if info.multi_pv == 1 {
+                    best_move = info.pv.first().copied();
+                }
             },
-        }
-        .search();
-        search_time += t.elapsed();
+        );
+        let elapsed = t.elapsed();
+        search_time += elapsed;
 
         total_nodes += nodes;
+        println!(
+            "{:>3}/{} {nodes:>10} nodes {:>7.3}s bestmove {} {root}",
+            i + 1,
+            positions.len(),
+            elapsed.as_secs_f64(),
+            best_move.map_or("(none)".to_string(), |mv| display_uci_move(root, mv)
+                .to_string()),
+        );
     }
 
     println!(
         "{total_nodes} nodes {} nps",
         ((total_nodes as f64) / search_time.as_secs_f64()) as u64
     );
+
+    if let Some(expected) = config.expected {
+        if total_nodes != expected {
+            eprintln!("bench signature mismatch: expected {expected}, found {total_nodes}");
+            exit(1);
+        }
+    }
+}
+
+/// Searches `root`, which follows the positions hashed in `history`, with one thread per entry of
+/// `locals`. Only the first thread observes `limits`, the clock and reports `info`; the others
+/// search until it stops them.
+pub fn search_threads(
+    shared: &SharedData,
+    locals: &mut [LocalData],
+    root: &Board,
+    history: &[u64],
+    limits: Limits,
+    clock: &dyn Fn() -> Duration,
+    info: &mut dyn FnMut(SearchInfo),
+) {
+    std::thread::scope(|s| {
+        let (main, helpers) = locals.split_first_mut().unwrap();
+        for data in helpers {
+            s.spawn(move || {
+                let mut limits = Limits::default();
+                limits.unbounded();
+                Search {
+                    root,
+                    history: history.to_vec(),
+                    clock: &|| Duration::ZERO,
+                    info: &mut |_| {},
+                    curr_move: &mut |_, _| {},
+                    data,
+                    shared,
+                    limits,
+                }
+                .search();
+            });
+        }
+
+        Search {
+            root,
+            history: history.to_vec(),
+            clock,
+            info,
+            curr_move: &mut |_, _| {},
+            data: main,
+            shared,
+            limits,
+        }
+        .search();
+    });
 }
 
 const BENCH_FENS: &[&str] = &[
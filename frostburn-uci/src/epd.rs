@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use cozy_chess::util::{display_san_move, parse_san_move, parse_uci_move};
+use cozy_chess::{Board, Move};
+use frostburn::{Limits, LocalData, SharedData};
+
+use crate::bench::{parse_position, search_threads};
+
+/// A test position along with the operations relevant to scoring it.
+struct Record {
+    board: Board,
+    id: Option<String>,
+    best: Vec<Move>,
+    avoid: Vec<Move>,
+    /// STS-style `c0 "Nf3=10, Bb5=7"` points per move.
+    points: Vec<(Move, u32)>,
+}
+
+impl Record {
+    fn is_scored(&self) -> bool {
+        !self.best.is_empty() || !self.avoid.is_empty() || !self.points.is_empty()
+    }
+
+    fn solves(&self, mv: Move) -> bool {
+        let best = match (self.best.is_empty(), self.points.is_empty()) {
+            (false, _) => self.best.contains(&mv),
+            (true, false) => self.points_for(mv) == self.max_points(),
+            (true, true) => true,
+        };
+        best && !self.avoid.contains(&mv)
+    }
+
+    fn points_for(&self, mv: Move) -> u32 {
+        self.points
+            .iter()
+            .find(|&&(m, _)| m == mv)
+            .map_or(0, |&(_, p)| p)
+    }
+
+    fn max_points(&self) -> u32 {
+        self.points.iter().map(|&(_, p)| p).max().unwrap_or(0)
+    }
+}
+
+fn parse_move(board: &Board, token: &str) -> Result<Move, String> {
+    let san = token.trim_end_matches(['!', '?']);
+    parse_san_move(board, san)
+        .or_else(|_| parse_uci_move(board, san))
+        .map_err(|_| format!("invalid move `{token}`"))
+}
+
+/// Splits the operations of an EPD record at semicolons which aren't inside a quoted string.
+fn split_operations(ops: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in ops.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                result.push(ops[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(ops[start..].trim());
+    result.retain(|op| !op.is_empty());
+    result
+}
+
+fn parse_record(line: &str) -> Result<Record, String> {
+    let mut rest = line.trim_start();
+    for _ in 0..4 {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    let position = &line[..line.len() - rest.len()];
+    let board = parse_position(position)?;
+
+    let mut record = Record {
+        board,
+        id: None,
+        best: vec![],
+        avoid: vec![],
+        points: vec![],
+    };
+    for op in split_operations(rest) {
+        let (opcode, operands) = op.split_once(char::is_whitespace).unwrap_or((op, ""));
+        let operands = operands.trim();
+        match opcode {
+            "id" => record.id = Some(operands.trim_matches('"').to_string()),
+            "bm" | "am" => {
+                let moves = operands
+                    .split_ascii_whitespace()
+                    .map(|mv| parse_move(&record.board, mv))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match opcode {
+                    "bm" => record.best = moves,
+                    _ => record.avoid = moves,
+                }
+            }
+            "c0" => {
+                for entry in operands.trim_matches('"').split(',') {
+                    // c0 is also used for free-form comments, which are ignored
+                    let Some((mv, points)) = entry.trim().split_once('=') else {
+                        continue;
+                    };
+                    let Ok(points) = points.trim().parse() else {
+                        continue;
+                    };
+                    record
+                        .points
+                        .push((parse_move(&record.board, mv.trim())?, points));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(record)
+}
+
+#[derive(Default)]
+struct Summary {
+    positions: usize,
+    solved: usize,
+    points: u32,
+    max_points: u32,
+    solve_time: Duration,
+}
+
+impl Summary {
+    fn print_row(&self, name: &str) {
+        let points = match self.max_points {
+            0 => "-".to_string(),
+            max => format!("{}/{max}", self.points),
+        };
+        let time = match self.solved {
+            0 => "-".to_string(),
+            n => format!("{:.3}s", self.solve_time.as_secs_f64() / n as f64),
+        };
+        println!(
+            "{name:<32} {solved:>11} {pct:>7} {points:>11} {time:>9}",
+            solved = format!("{}/{}", self.solved, self.positions),
+            pct = format!(
+                "{:.1}%",
+                self.solved as f64 * 100.0 / self.positions.max(1) as f64
+            ),
+        );
+    }
+
+    fn add(&mut self, other: &Summary) {
+        self.positions += other.positions;
+        self.solved += other.solved;
+        self.points += other.points;
+        self.max_points += other.max_points;
+        self.solve_time += other.solve_time;
+    }
+}
+
+/// `epd [options] <file>...`: searches every position of the given test suites and reports which
+/// were solved.
+pub fn run(mut args: impl Iterator<Item = String>) {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: epd [--time MS] [--depth N] [--nodes N] [--hash MB] [--threads N] <file>..."
+        );
+        eprintln!("positions are scored using their bm, am and STS-style c0 operations");
+        eprintln!("the search is limited to 1000ms per position unless a limit is given");
+        exit(1);
+    };
+
+    let mut limits = Limits::default();
+    let mut hash_mb = 64;
+    let mut threads = 1;
+    let mut files = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match &*arg {
+            "--time" => {
+                let ms = value().parse().unwrap_or_else(|_| usage());
+                limits.move_time = Some(Duration::from_millis(ms));
+            }
+            "--depth" => limits.depth = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--nodes" => limits.nodes = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--hash" => hash_mb = value().parse().unwrap_or_else(|_| usage()),
+            "--threads" => threads = value().parse().unwrap_or_else(|_| usage()),
+            _ if arg.starts_with("--") => usage(),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() || threads == 0 {
+        usage();
+    }
+    if limits.move_time.is_none() && limits.depth.is_none() && limits.nodes.is_none() {
+        limits.move_time = Some(Duration::from_millis(1000));
+    }
+
+    let mut shared = SharedData::new(hash_mb);
+    let mut locals: Vec<_> = (0..threads).map(|_| LocalData::new()).collect();
+    let mut groups: BTreeMap<String, Summary> = BTreeMap::new();
+    let mut total = Summary::default();
+
+    for path in &files {
+        let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("failed to read {path}: {e}");
+            exit(1);
+        });
+        let stem = Path::new(path)
+            .file_stem()
+            .map_or(path.clone(), |s| s.to_string_lossy().into_owned());
+
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let record = match parse_record(line) {
+                Ok(record) => record,
+                Err(e) => {
+                    eprintln!("{path}:{}: {e}", i + 1);
+                    continue;
+                }
+            };
+            let id = record
+                .id
+                .clone()
+                .unwrap_or_else(|| format!("{stem}:{}", i + 1));
+
+            // positions are searched independently of each other
+            for block in shared.get_clear_tt_blocks(1) {
+                shared.clear_tt_block(block);
+            }
+            for local in &mut locals {
+                *local = LocalData::new();
+            }
+            shared.prepare_for_search();
+
+            let mut best_move = None;
+            let mut depth = 0;
+            let mut solved_at = None;
+            let start = Instant::now();
+            search_threads(
+                &shared,
+                &mut locals,
+                &record.board,
+                &[],
+                limits.clone(),
+                &|| start.elapsed(),
+                &mut |info| {
+                    if info.multi_pv != 1 {
+                        return;
+                    }
+                    let Some(&mv) = info.pv.first() else { return };
+                    best_move = Some(mv);
+                    depth = info.depth;
+                    match record.solves(mv) {
+                        true => solved_at = solved_at.or(Some(info.time)),
+                        false => solved_at = None,
+                    }
+                },
+            );
+
+            let Some(mv) = best_move else {
+                eprintln!("{id}: no legal moves");
+                continue;
+            };
+            if !record.is_scored() {
+                println!("{id:<32} -    {}", display_san_move(&record.board, mv));
+                continue;
+            }
+            let solved = record.solves(mv);
+            let result = Summary {
+                positions: 1,
+                solved: solved as usize,
+                points: record.points_for(mv),
+                max_points: record.max_points(),
+                solve_time: solved_at.unwrap_or_default(),
+            };
+
+            let san = |mv| display_san_move(&record.board, mv).to_string();
+            let expected = match record.best.is_empty() {
+                true => record
+                    .avoid
+                    .iter()
+                    .map(|&mv| format!("!{}", san(mv)))
+                    .collect::<Vec<_>>(),
+                false => record.best.iter().map(|&mv| san(mv)).collect(),
+            };
+            print!(
+                "{id:<32} {status:<4} {mv:<7} {expected:<16} depth {depth:<3}",
+                status = match solved {
+                    true => "ok",
+                    false => "FAIL",
+                },
+                mv = san(mv),
+                expected = expected.join(" "),
+            );
+            if result.max_points > 0 {
+                print!(" {}/{}", result.points, result.max_points);
+            }
+            match solved_at {
+                Some(time) => println!(" solved in {:.3}s", time.as_secs_f64()),
+                None => println!(),
+            }
+
+            // ids like `WAC.001` or `STS(v1.0) Undermine.001` are grouped by their prefix
+            let group = match id.rsplit_once('.') {
+                Some((prefix, number)) if number.bytes().all(|b| b.is_ascii_digit()) => {
+                    prefix.to_string()
+                }
+                _ => stem.clone(),
+            };
+            groups.entry(group).or_default().add(&result);
+            total.add(&result);
+        }
+    }
+
+    println!();
+    println!(
+        "{:<32} {:>11} {:>7} {:>11} {:>9}",
+        "suite", "solved", "", "points", "avg time"
+    );
+    for (name, summary) in &groups {
+        summary.print_row(name);
+    }
+    if groups.len() > 1 {
+        total.print_row("total");
+    }
+}
@@ -1,28 +1,52 @@
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::io::prelude::Write;
-use std::io::{stdin, stdout};
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::prelude::{Read, Write};
+use std::io::{stdin, stdout, ErrorKind};
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
-use cozy_chess::util::{display_san_move, display_uci_move, parse_uci_move};
-use cozy_chess::{Board, BoardBuilder, Color, Piece, Square};
+use cozy_chess::util::{display_san_move, display_uci_move};
+use cozy_chess::{Board, BoardBuilder, Color, Move, Piece, Square};
 use frostburn::{
-    ClearTtBlock, Limits, LocalData, Nnue, NnueBackend, Search, SearchInfo, SharedData,
+    ClearTtBlock, InvalidHashFile, Limits, LocalData, Nnue, NnueBackend, NnueNetwork, Search,
+    SearchInfo, SharedData, Tablebase, MAX_TT_MB,
 };
 
+use crate::book::Book;
+
+mod annotate;
 mod bench;
+mod book;
+mod epd;
+mod parse;
+mod perft;
 mod reproduce;
+mod selfplay;
+mod strength;
+mod tablebase;
+mod wdlfit;
+mod xboard;
 
 type TokenIter<'a> = std::str::SplitAsciiWhitespace<'a>;
-type CmdHandler = fn(&mut UciHandler, &mut TokenIter);
+type CmdHandler = fn(&mut UciHandler, &mut TokenIter) -> Result<(), String>;
 
 fn main() {
     let mut args = std::env::args().skip(1);
     match args.next().as_deref() {
-        Some("bench") => bench::bench(),
+        Some("annotate") => annotate::run(args),
+        Some("bench") => bench::run(args),
+        Some("calibrate") => strength::run(args),
+        Some("epd") => epd::run(args),
+        Some("match") => selfplay::run(args),
+        Some("perft") => perft::run(args),
+        Some("tbgen") => tablebase::run(args),
+        Some("wdlfit") => wdlfit::run(args),
         Some("reproduce") => {
             let err = || -> ! {
                 eprintln!("usage: reproduce <white|black> <hash>");
@@ -65,27 +89,43 @@ fn main() {
             cmds.insert("quit", UciHandler::quit);
             cmds.insert("position", UciHandler::position);
             cmds.insert("go", UciHandler::go);
-            cmds.insert("debug", |_, _| {});
+            cmds.insert("debug", |_, _| Ok(()));
             cmds.insert("setoption", UciHandler::set_option);
             cmds.insert("ucinewgame", UciHandler::new_game);
             cmds.insert("stop", UciHandler::stop);
+            cmds.insert("ponderhit", UciHandler::ponderhit);
             cmds.insert("eval", UciHandler::eval);
+            cmds.insert("d", UciHandler::display);
             cmds.insert("wait", UciHandler::wait);
 
             let mut uci = UciHandler::new();
             let mut buf = String::new();
             loop {
                 buf.clear();
-                if stdin().read_line(&mut buf).unwrap() == 0 {
-                    exit(0);
+                match stdin().read_line(&mut buf) {
+                    Ok(0) => exit(0),
+                    Ok(_) => {}
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        println!("info string {e}");
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("{e}");
+                        exit(1);
+                    }
                 }
                 let mut tokens = buf.split_ascii_whitespace();
                 let Some(cmd) = tokens.next() else { continue };
-                if let Some(&handler) = cmds.get(cmd) {
-                    handler(&mut uci, &mut tokens);
-                } else {
-                    println!("info sting Unknown command {cmd}");
+                if cmd == "xboard" || cmd == "protover" {
+                    xboard::run(&mut uci, &buf);
+                }
+                let Some(&handler) = cmds.get(cmd) else {
+                    println!("info string unknown command `{cmd}`");
+                    continue;
                 };
+                if let Err(e) = handler(&mut uci, &mut tokens) {
+                    println!("info string {e}");
+                }
             }
         }
     }
@@ -101,7 +141,23 @@ struct SearchConfig {
     history: Vec<u64>,
     mv_format: MoveFormat,
     limits: Limits,
-    pretty: bool,
+    protocol: Protocol,
+    post: bool,
+    show_wdl: bool,
+    limit_strength: bool,
+    elo: i32,
+    thread_nodes: Vec<AtomicU64>,
+    started: AtomicUsize,
+    running: AtomicUsize,
+    xboard_move: Mutex<Option<Move>>,
+    discard_move: AtomicBool,
+    /// Set by `stop` to end a `go perft`, which counts without holding the config.
+    abort_perft: Arc<AtomicBool>,
+    book: Option<Book>,
+    own_book: bool,
+    best_book_move: bool,
+    book_depth: u16,
+    hash_file: String,
 
     start: Instant,
 }
@@ -111,9 +167,37 @@ enum Command {
     ClearTt(ClearTtBlock),
     ResetData,
     Search,
+    Perft(u8),
     Rendezvous,
 }
 
+enum HashFileError {
+    Io(std::io::Error),
+    Invalid(InvalidHashFile),
+}
+
+impl From<InvalidHashFile> for HashFileError {
+    fn from(e: InvalidHashFile) -> Self {
+        HashFileError::Invalid(e)
+    }
+}
+
+impl std::fmt::Display for HashFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashFileError::Io(e) => write!(f, "{e}"),
+            HashFileError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Protocol {
+    Pretty,
+    Uci,
+    Xboard,
+}
+
 #[derive(Copy, Clone, Debug)]
 enum MoveFormat {
     Standard,
@@ -122,6 +206,9 @@ enum MoveFormat {
 
 impl UciHandler {
     fn new() -> UciHandler {
+        let mut shared = SharedData::new(64);
+        // searches waiting for `stop` or `ponderhit` shouldn't occupy a core
+        shared.idle = || std::thread::sleep(Duration::from_millis(1));
         let mut this = UciHandler {
             shared_data: Arc::new(RwLock::new((
                 SearchConfig {
@@ -129,26 +216,65 @@ impl UciHandler {
                     history: vec![],
                     start: Instant::now(),
                     mv_format: MoveFormat::Standard,
-                    pretty: true,
-                    limits: Limits::default(),
+                    protocol: Protocol::Pretty,
+                    post: true,
+                    show_wdl: false,
+                    limit_strength: false,
+                    elo: strength::MAX_ELO,
+                    thread_nodes: vec![],
+                    started: AtomicUsize::new(0),
+                    running: AtomicUsize::new(0),
+                    xboard_move: Mutex::new(None),
+                    discard_move: AtomicBool::new(false),
+                    abort_perft: Arc::new(AtomicBool::new(false)),
+                    book: None,
+                    own_book: false,
+                    best_book_move: false,
+                    book_depth: 20,
+                    hash_file: "frostburn.hash".to_owned(),
+                    limits: Limits {
+                        move_overhead: Duration::from_millis(10),
+                        ..Default::default()
+                    },
                 },
-                SharedData::new(64),
+                shared,
             ))),
             threads: vec![],
         };
-        this.set_option(&mut "name Threads value 1".split_ascii_whitespace());
+        this.set_threads(1);
         this
     }
 
-    fn uci(&mut self, _: &mut TokenIter) {
+    fn uci(&mut self, _: &mut TokenIter) -> Result<(), String> {
         let mut shared = self.shared_data.write().unwrap();
-        shared.0.pretty = false;
+        shared.0.protocol = Protocol::Uci;
 
         println!("id name Frostburn {}", env!("CARGO_PKG_VERSION_MAJOR"));
         println!("id author {}", env!("CARGO_PKG_AUTHORS"));
         println!("option name UCI_Chess960 type check default false");
-        println!("option name Hash type spin min 1 max 1048576 default 64");
+        println!("option name UCI_ShowWDL type check default false");
+        println!("option name Hash type spin min 1 max {MAX_TT_MB} default 64");
+        println!("option name Clear Hash type button");
+        println!("option name HashFile type string default frostburn.hash");
+        println!("option name Save Hash type button");
+        println!("option name Load Hash type button");
         println!("option name Threads type spin min 1 max 1024 default 1");
+        println!("option name MultiPV type spin min 1 max 256 default 1");
+        println!("option name Ponder type check default false");
+        println!("option name OwnBook type check default false");
+        println!("option name BookFile type string default <empty>");
+        println!("option name BookDepth type spin min 1 max 1000 default 20");
+        println!("option name BestBookMove type check default false");
+        println!("option name Move Overhead type spin min 0 max 5000 default 10");
+        println!("option name UCI_LimitStrength type check default false");
+        println!(
+            "option name UCI_Elo type spin min {} max {} default {}",
+            strength::MIN_ELO,
+            strength::MAX_ELO,
+            strength::MAX_ELO
+        );
+        println!("option name EvalFile type string default <embedded>");
+        println!("option name TablebasePath type string default <empty>");
         println!("option name Weaken_Eval type spin min 0 max 10000 default 0");
 
         print!(
@@ -169,117 +295,212 @@ impl UciHandler {
         }
 
         println!("uciok");
+        print_tt_pages(&shared.1);
+        Ok(())
     }
 
-    fn is_ready(&mut self, _: &mut TokenIter) {
+    fn is_ready(&mut self, _: &mut TokenIter) -> Result<(), String> {
         println!("readyok");
+        Ok(())
     }
 
-    fn quit(&mut self, _: &mut TokenIter) {
+    fn quit(&mut self, _: &mut TokenIter) -> Result<(), String> {
         exit(0)
     }
 
-    fn set_option(&mut self, tokens: &mut TokenIter) {
-        let _name_token = tokens.next();
+    fn set_option(&mut self, tokens: &mut TokenIter) -> Result<(), String> {
+        match tokens.next() {
+            Some("name") => {}
+            _ => return Err("expected `setoption name <id> [value <x>]`".to_owned()),
+        }
+        let name = tokens
+            .by_ref()
+            .take_while(|&tok| tok != "value")
+            .collect::<Vec<_>>()
+            .join(" ");
+        let value = tokens.collect::<Vec<_>>().join(" ");
+        let value = (!value.is_empty()).then_some(&*value);
         let mut guard = self.shared_data.write().unwrap();
         let (config, shared) = &mut *guard;
-        match tokens.next().unwrap() {
+        match &*name {
             "UCI_Chess960" => {
-                config.mv_format = match tokens.nth(1).unwrap() {
-                    "true" => MoveFormat::Chess960,
-                    "false" => MoveFormat::Standard,
-                    _ => panic!("invalid value for UCI_Chess960"),
+                config.mv_format = match parse::check(&name, value)? {
+                    true => MoveFormat::Chess960,
+                    false => MoveFormat::Standard,
                 }
             }
+            "UCI_ShowWDL" => config.show_wdl = parse::check(&name, value)?,
+            "UCI_LimitStrength" => config.limit_strength = parse::check(&name, value)?,
+            "UCI_Elo" => {
+                config.elo = parse::spin(&name, value, strength::MIN_ELO, strength::MAX_ELO)?;
+            }
+            "Ponder" => {
+                parse::check(&name, value)?;
+            }
+            "OwnBook" => config.own_book = parse::check(&name, value)?,
+            "BestBookMove" => config.best_book_move = parse::check(&name, value)?,
+            "BookDepth" => config.book_depth = parse::spin(&name, value, 1, 1000)?,
+            "BookFile" => {
+                config.book = match value {
+                    None | Some("<empty>") => None,
+                    Some(path) => {
+                        let book = Book::load(path)
+                            .map_err(|e| format!("failed to load BookFile `{path}`: {e}"))?;
+                        println!(
+                            "info string loaded BookFile {path} ({} entries)",
+                            book.len()
+                        );
+                        Some(book)
+                    }
+                };
+            }
             "Hash" => {
-                let backend = shared.nnue_backend;
-                let mb = tokens.nth(1).unwrap().parse().unwrap();
-                *shared = SharedData::new(mb);
-                shared.nnue_backend = backend;
+                let mb = parse::spin(&name, value, 1, MAX_TT_MB)?;
+                shared.resize_tt(mb);
+                print_tt_pages(shared);
+                drop(guard);
+                self.clear_tt();
             }
-            "Threads" => {
-                let num = tokens.nth(1).unwrap().parse().unwrap();
-                for (send, t) in self.threads.drain(..) {
-                    send.send(Command::Exit).unwrap();
-                    t.join().unwrap();
-                }
-                for id in 0..num {
-                    let (send, recv) = sync_channel(0);
-                    let shared = self.shared_data.clone();
-                    self.threads.push((
-                        send,
-                        std::thread::spawn(move || search_thread(shared, recv, id)),
-                    ));
+            "Clear Hash" => {
+                drop(guard);
+                self.clear_tt();
+            }
+            "HashFile" => {
+                config.hash_file = value
+                    .ok_or_else(|| format!("missing value for {name}"))?
+                    .to_owned();
+            }
+            "Save Hash" => {
+                let path = &config.hash_file;
+                let mut file = File::create(path)
+                    .map_err(|e| format!("failed to create HashFile `{path}`: {e}"))?;
+                shared
+                    .save_tt(|bytes| file.write_all(bytes))
+                    .map_err(|e| format!("failed to write HashFile `{path}`: {e}"))?;
+                println!("info string saved hash to {path} ({} MB)", shared.tt_mb());
+            }
+            "Load Hash" => {
+                let path = &config.hash_file;
+                let mut file = File::open(path)
+                    .map_err(|e| format!("failed to open HashFile `{path}`: {e}"))?;
+                let old_mb = shared.tt_mb();
+                let result = shared.load_tt(|buf| file.read_exact(buf).map_err(HashFileError::Io));
+                if shared.tt_mb() != old_mb {
+                    println!(
+                        "info string Hash changed from {old_mb} MB to {} MB to match {path}",
+                        shared.tt_mb()
+                    );
+                    print_tt_pages(shared);
                 }
+                result.map_err(|e| format!("failed to load HashFile `{path}`: {e}"))?;
+                println!(
+                    "info string loaded hash from {path} ({} MB)",
+                    shared.tt_mb()
+                );
+            }
+            "Threads" => {
+                let num = parse::spin(&name, value, 1, 1024)?;
+                drop(guard);
+                self.set_threads(num);
+            }
+            "MultiPV" => {
+                config.limits.multi_pv = parse::spin(&name, value, 1, 256)?;
+            }
+            "Move Overhead" => {
+                config.limits.move_overhead =
+                    Duration::from_millis(parse::spin(&name, value, 0, 5000)?);
             }
             "Weaken_Eval" => {
-                config.limits.quantize_eval = tokens.nth(1).unwrap().parse::<i16>().unwrap() + 1;
+                config.limits.quantize_eval = parse::spin(&name, value, 0, 10000)? + 1;
             }
             "NNUE_Backend" => {
-                let name = tokens.nth(1).unwrap();
-                for backend in NnueBackend::available() {
-                    if name == backend.name() {
-                        shared.nnue_backend = backend;
-                        break;
+                let value = value.ok_or_else(|| format!("missing value for {name}"))?;
+                shared.nnue_backend = NnueBackend::available()
+                    .into_iter()
+                    .find(|backend| backend.name() == value)
+                    .ok_or_else(|| format!("unavailable NNUE backend `{value}`"))?;
+            }
+            "EvalFile" => {
+                shared.nnue_network = match value {
+                    None | Some("<embedded>") => NnueNetwork::embedded(),
+                    Some(path) => {
+                        let bytes = std::fs::read(path)
+                            .map_err(|e| format!("failed to read EvalFile `{path}`: {e}"))?;
+                        let network = NnueNetwork::from_bytes(&bytes)
+                            .map_err(|e| format!("invalid EvalFile `{path}`: {e}"))?;
+                        println!("info string loaded EvalFile {path}");
+                        network
                     }
-                }
+                };
+            }
+            "TablebasePath" => {
+                shared.tablebase = match value {
+                    None | Some("<empty>") => Tablebase::default(),
+                    Some(path) => {
+                        let tablebase = tablebase::load(path)?;
+                        println!(
+                            "info string loaded {} tablebase files from {path}",
+                            tablebase.len()
+                        );
+                        tablebase
+                    }
+                };
             }
             #[cfg(feature = "tunable")]
             param => {
-                let v = tokens.nth(1).unwrap().parse().unwrap();
-                for tunable in frostburn::TUNABLES {
-                    if param == tunable.name {
-                        tunable.atomic.store(v, std::sync::atomic::Ordering::SeqCst);
-                    }
-                }
+                let tunable = frostburn::TUNABLES
+                    .iter()
+                    .find(|tunable| tunable.name == param)
+                    .ok_or_else(|| format!("unknown option `{name}`"))?;
+                let v = parse::spin(&name, value, tunable.min, tunable.max)?;
+                tunable.atomic.store(v, std::sync::atomic::Ordering::SeqCst);
             }
             #[allow(
                 unreachable_patterns,
                 reason = "suppress warning when tunable feature enabled"
             )]
-            _ => {}
+            _ => return Err(format!("unknown option `{name}`")),
         }
+        Ok(())
     }
 
-    fn position(&mut self, tokens: &mut TokenIter) {
-        let mut tokens = tokens.peekable();
-        let mut guard = self.shared_data.write().unwrap();
-        let (config, _) = &mut *guard;
-
-        match tokens.next().unwrap() {
-            "startpos" => config.position = Board::startpos(),
-            "fen" => {
-                let mut fen = tokens
-                    .by_ref()
-                    .take(4)
-                    .fold(String::new(), |s, t| s + t + " ");
-                fen += tokens.next_if(|&tok| tok != "moves").unwrap_or("0");
-                fen += " ";
-                fen += tokens.next_if(|&tok| tok != "moves").unwrap_or("1");
-                config.position = fen.trim().parse().unwrap();
-            }
-            unknown => panic!("unknown position type {unknown}"),
+    fn set_threads(&mut self, num: usize) {
+        for (send, t) in self.threads.drain(..) {
+            send.send(Command::Exit).unwrap();
+            t.join().unwrap();
         }
+        self.shared_data.write().unwrap().0.thread_nodes =
+            (0..num).map(|_| AtomicU64::new(0)).collect();
+        for id in 0..num {
+            let (send, recv) = sync_channel(0);
+            let shared = self.shared_data.clone();
+            self.threads.push((
+                send,
+                std::thread::spawn(move || search_thread(shared, recv, id)),
+            ));
+        }
+    }
 
-        config.history.clear();
-
-        let _moves_token = tokens.next();
-
-        for mv in tokens {
-            let mv = match config.mv_format {
-                MoveFormat::Standard => parse_uci_move(&config.position, mv).unwrap(),
-                MoveFormat::Chess960 => mv.parse().unwrap(),
-            };
+    fn position(&mut self, tokens: &mut TokenIter) -> Result<(), String> {
+        let mut guard = self.shared_data.write().unwrap();
+        let (config, _) = &mut *guard;
 
-            config.history.push(config.position.hash());
-            config.position.play(mv);
-        }
+        let (position, history) = parse::position(&mut tokens.peekable(), config.mv_format)?;
+        config.position = position;
+        config.history = history;
+        Ok(())
     }
 
-    fn new_game(&mut self, _: &mut TokenIter) {
+    fn new_game(&mut self, _: &mut TokenIter) -> Result<(), String> {
         for (send, _) in &self.threads {
             send.send(Command::ResetData).unwrap();
         }
+        self.clear_tt();
+        Ok(())
+    }
+
+    /// Zeroes the transposition table, splitting the work across the search threads.
+    fn clear_tt(&mut self) {
         let blocks = self
             .shared_data
             .read()
@@ -289,30 +510,42 @@ impl UciHandler {
         for ((send, _), block) in self.threads.iter().zip(blocks) {
             send.send(Command::ClearTt(block)).unwrap();
         }
-        for (send, _) in &self.threads {
-            send.send(Command::Rendezvous).unwrap();
-        }
+        self.rendezvous();
     }
 
-    fn stop(&mut self, _: &mut TokenIter) {
-        self.shared_data.read().unwrap().1.abort();
-        for (send, _) in &self.threads {
-            send.send(Command::Rendezvous).unwrap();
-        }
+    fn stop(&mut self, _: &mut TokenIter) -> Result<(), String> {
+        let guard = self.shared_data.read().unwrap();
+        guard.0.abort_perft.store(true, Ordering::SeqCst);
+        guard.1.abort();
+        drop(guard);
+        self.rendezvous();
+        Ok(())
     }
 
-    fn wait(&mut self, _: &mut TokenIter) {
+    fn ponderhit(&mut self, _: &mut TokenIter) -> Result<(), String> {
+        self.shared_data.read().unwrap().1.ponderhit();
+        Ok(())
+    }
+
+    fn wait(&mut self, _: &mut TokenIter) -> Result<(), String> {
+        self.rendezvous();
+        Ok(())
+    }
+
+    /// Blocks until every search thread is idle.
+    fn rendezvous(&mut self) {
         for (send, _) in &self.threads {
             send.send(Command::Rendezvous).unwrap();
         }
     }
 
-    fn eval(&mut self, _: &mut TokenIter) {
+    fn eval(&mut self, _: &mut TokenIter) -> Result<(), String> {
         let mut acc = Nnue::new();
-        let backend = self.shared_data.read().unwrap().1.nnue_backend;
         let guard = self.shared_data.read().unwrap();
-        let config = &guard.0;
-        let static_eval = acc.infer(&config.position, backend);
+        let (config, shared) = &*guard;
+        let backend = shared.nnue_backend;
+        let network = &shared.nnue_network;
+        let static_eval = acc.infer(&config.position, backend, network);
         let mut others = [None; 64];
         let remove_pieces = config.position.occupied() - config.position.pieces(Piece::King);
         for sq in remove_pieces {
@@ -323,7 +556,7 @@ impl UciHandler {
             board.castle_rights[1].short = None;
             board.castle_rights[1].long = None;
             if let Ok(board) = board.build() {
-                others[sq as usize] = Some(acc.infer(&board, backend));
+                others[sq as usize] = Some(acc.infer(&board, backend, network));
             }
         }
 
@@ -363,58 +596,198 @@ impl UciHandler {
         println!("+");
 
         println!("{static_eval}");
+        Ok(())
+    }
+
+    fn display(&mut self, _: &mut TokenIter) -> Result<(), String> {
+        let guard = self.shared_data.read().unwrap();
+        let (config, shared) = &*guard;
+        let board = &config.position;
+
+        for rank in (0..8).rev() {
+            println!(" +---+---+---+---+---+---+---+---+");
+            for file in 0..8 {
+                let sq = Square::index(rank * 8 + file);
+                let piece = match (board.piece_on(sq), board.color_on(sq)) {
+                    (Some(piece), Some(Color::White)) => piece.to_string().to_uppercase(),
+                    (Some(piece), _) => piece.to_string(),
+                    (None, _) => " ".to_owned(),
+                };
+                print!(" | {piece}");
+            }
+            println!(" | {}", rank + 1);
+        }
+        println!(" +---+---+---+---+---+---+---+---+");
+        println!("   a   b   c   d   e   f   g   h");
+        println!();
+
+        match config.mv_format {
+            MoveFormat::Standard => println!("Fen: {board}"),
+            MoveFormat::Chess960 => println!("Fen: {board:#}"),
+        }
+        println!("Key: {:016X}", board.hash());
+        print!("Checkers:");
+        for sq in board.checkers() {
+            print!(" {sq}");
+        }
+        println!();
+
+        let mut legal_moves = 0;
+        board.generate_moves(|mvs| {
+            legal_moves += mvs.len();
+            false
+        });
+        println!("Legal moves: {legal_moves}");
+        println!("History: {}", config.history.len());
+
+        let eval = Nnue::new().infer(board, shared.nnue_backend, &shared.nnue_network);
+        println!("NNUE eval: {eval:+} (side to move)");
+        Ok(())
     }
 
-    fn go(&mut self, tokens: &mut TokenIter) {
+    fn go(&mut self, tokens: &mut TokenIter) -> Result<(), String> {
         let start = Instant::now();
 
         let mut guard = self.shared_data.write().unwrap();
-        let (config, shared) = &mut *guard;
+        let config = &mut guard.0;
 
         let white = config.position.side_to_move() == Color::White;
 
         config.limits.unbounded();
+        config.limits.infinite = false;
+        config.limits.search_moves.clear();
+        config.limits.mate_search = false;
         config.start = start;
 
+        let mut ponder = false;
+        let mut perft_depth = None;
+        let mut tokens = tokens.peekable();
+
+        // a bad parameter is reported and ignored, since refusing to search would lose on time
         while let Some(limit_verb) = tokens.next() {
-            let mut number = |if_negative: u64| {
-                tokens
-                    .next()
-                    .unwrap()
-                    .parse::<i64>()
-                    .unwrap()
-                    .try_into()
-                    .unwrap_or(if_negative)
+            let limits = &mut config.limits;
+            let mut number = || parse::number(limit_verb, tokens.next());
+            let result = match limit_verb {
+                "movetime" => number().map(|n| limits.move_time = Some(Duration::from_millis(n))),
+                "depth" => number().map(|n| limits.depth = Some(n.min(i16::MAX as u64) as i16)),
+                "nodes" => number().map(|n| limits.nodes = Some(n)),
+                "minnodes" => number().map(|n| limits.min_nodes = Some(n)),
+                "mate" => number().map(|n| {
+                    limits.mate = Some(n.min(i16::MAX as u64) as i16);
+                    limits.mate_search = true;
+                }),
+                "wtime" | "btime" => number().map(|n| {
+                    if white == (limit_verb == "wtime") {
+                        limits.clock = Some(Duration::from_millis(n));
+                    }
+                }),
+                "winc" | "binc" => number().map(|n| {
+                    if white == (limit_verb == "winc") {
+                        limits.increment = Duration::from_millis(n);
+                    }
+                }),
+                "movestogo" => number().map(|n| limits.moves_to_go = Some(n as u32)),
+                "ponder" => {
+                    ponder = true;
+                    Ok(())
+                }
+                "perft" => number().map(|n| perft_depth = Some(n.min(u8::MAX as u64) as u8)),
+                "infinite" => {
+                    limits.infinite = true;
+                    Ok(())
+                }
+                "searchmoves" => {
+                    while let Some(token) = tokens.next_if(|tok| tok.parse::<Move>().is_ok()) {
+                        match parse::parse_move(&config.position, token, config.mv_format) {
+                            Ok(mv) => limits.search_moves.push(mv),
+                            Err(e) => println!("info string {e}"),
+                        }
+                    }
+                    Ok(())
+                }
+                unknown => Err(format!("unknown go parameter `{unknown}`")),
             };
-            match limit_verb {
-                "movetime" => config.limits.move_time = Some(Duration::from_millis(number(0))),
-                "depth" => config.limits.depth = Some(number(0) as i16),
-                "nodes" => config.limits.nodes = Some(number(0)),
-                "minnodes" => config.limits.min_nodes = Some(number(0)),
-                "wtime" if white => config.limits.clock = Some(Duration::from_millis(number(0))),
-                "btime" if !white => config.limits.clock = Some(Duration::from_millis(number(0))),
-                "winc" if white => config.limits.increment = Duration::from_millis(number(0)),
-                "binc" if !white => config.limits.increment = Duration::from_millis(number(0)),
-                _ => {}
+            if let Err(e) = result {
+                println!("info string {e}");
             }
         }
 
-        shared.prepare_for_search();
+        // perft runs on the main search thread so `stop` and other commands stay responsive
+        if let Some(depth) = perft_depth {
+            config.abort_perft.store(false, Ordering::SeqCst);
+            drop(guard);
+            self.threads[0].0.send(Command::Perft(depth)).unwrap();
+            return Ok(());
+        }
+
+        // the GUI still expects a reply when there are no legal moves
+        if !config.position.generate_moves(|_| true) {
+            println!("info string no legal moves in position {}", config.position);
+            println!("bestmove 0000");
+            return Ok(());
+        }
+
+        if let Some(mv) = book_move(config, ponder) {
+            let mv = format_move(&config.position, mv, config.mv_format);
+            println!("info string book move {mv}");
+            println!("bestmove {mv}");
+            return Ok(());
+        }
+
+        drop(guard);
+        self.start_search(ponder);
+        Ok(())
+    }
+
+    fn start_search(&mut self, ponder: bool) {
+        let mut guard = self.shared_data.write().unwrap();
+        let (config, shared) = &mut *guard;
+
+        config.limits.eval_noise = 0;
+        config.limits.root_noise = 0;
+        if config.limit_strength {
+            strength::limit_strength(&mut config.limits, config.elo);
+            shared.seed = RandomState::new().build_hasher().finish();
+        }
+
+        *config.started.get_mut() = 0;
+        *config.discard_move.get_mut() = false;
+
+        match ponder {
+            true => shared.prepare_for_ponder(),
+            false => shared.prepare_for_search(),
+        }
 
         drop(guard);
         for (send, _) in &self.threads {
             send.send(Command::Search).unwrap();
         }
+
+        // helpers must hold the config before we accept commands which could change it
+        let guard = self.shared_data.read().unwrap();
+        while guard.0.started.load(Ordering::SeqCst) != self.threads.len() {
+            std::thread::yield_now();
+        }
     }
 }
 
-fn print_info(root: &Board, mv_format: MoveFormat, info: &SearchInfo) {
+fn print_info(root: &Board, mv_format: MoveFormat, show_wdl: bool, info: &SearchInfo) {
     print!(
-        "info depth {d} seldepth {sd} score {s} nodes {n} time {t} nps {nps} pv",
+        "info depth {d} seldepth {sd} multipv {k} score {s}",
         d = info.depth,
         sd = info.selective_depth,
+        k = info.multi_pv,
         s = info.score,
+    );
+    if show_wdl {
+        let (w, d, l) = info.wdl;
+        print!(" wdl {w} {d} {l}");
+    }
+    print!(
+        " nodes {n} time {t} nps {nps} hashfull {h} tbhits {tb} pv",
         n = info.nodes,
+        h = info.hashfull,
+        tb = info.tb_hits,
         t = info.time.as_millis(),
         nps = (info.nodes as f64 / info.time.as_secs_f64()) as u64,
     );
@@ -431,7 +804,46 @@ fn print_info(root: &Board, mv_format: MoveFormat, info: &SearchInfo) {
     println!();
 }
 
-fn print_info_pretty(root: &Board, info: &SearchInfo) {
+/// Huge pages can only be requested, so whether the kernel provides them isn't reported.
+fn print_tt_pages(shared: &SharedData) {
+    match shared.tt_huge_pages_requested() {
+        true => println!(
+            "info string requested huge pages for {} MB hash",
+            shared.tt_mb()
+        ),
+        false => println!(
+            "info string using normal pages for {} MB hash",
+            shared.tt_mb()
+        ),
+    }
+}
+
+/// Looks up the position in the opening book, if one is in use and the game is still early enough.
+/// Ponder and infinite searches always search, since the GUI decides when those end.
+fn book_move(config: &SearchConfig, ponder: bool) -> Option<Move> {
+    let book = config.book.as_ref().filter(|_| config.own_book)?;
+    if ponder
+        || config.limits.infinite
+        || !config.limits.search_moves.is_empty()
+        || config.position.fullmove_number() > config.book_depth
+    {
+        return None;
+    }
+    let random = RandomState::new().build_hasher().finish();
+    book.probe(&config.position, config.best_book_move, random)
+}
+
+fn format_move(board: &Board, mv: Move, mv_format: MoveFormat) -> String {
+    match mv_format {
+        MoveFormat::Standard => display_uci_move(board, mv).to_string(),
+        MoveFormat::Chess960 => mv.to_string(),
+    }
+}
+
+fn print_info_pretty(root: &Board, multi_pv: usize, info: &SearchInfo) {
+    if multi_pv > 1 {
+        print!("{:>3}. ", info.multi_pv);
+    }
     print!(
         "{:2} / {:2} {:>#6} {:>8.3}s {:>12} ",
         info.depth,
@@ -468,30 +880,49 @@ fn search_thread(
                 continue;
             }
             Command::Rendezvous => continue,
+            Command::Perft(depth) => {
+                let guard = shared_data.read().unwrap();
+                let config = &guard.0;
+                let board = config.position.clone();
+                let threads = config.thread_nodes.len();
+                let chess960 = matches!(config.mv_format, MoveFormat::Chess960);
+                let abort = config.abort_perft.clone();
+                drop(guard);
+
+                perft::go_perft(&board, depth, threads, chess960, &abort);
+                stdout().flush().unwrap();
+                continue;
+            }
             Command::Search => {}
         }
 
         let guard = shared_data.read().unwrap();
         let (config, shared) = &*guard;
+        config.running.fetch_add(1, Ordering::SeqCst);
+        config.started.fetch_add(1, Ordering::SeqCst);
 
-        let mut limits = config.limits;
+        let mut limits = config.limits.clone();
+        let mut best_pv = vec![];
 
         let info: &mut dyn FnMut(SearchInfo) = match id {
             0 => &mut |info| {
-                if config.pretty {
-                    print_info_pretty(&config.position, &info);
-                } else {
-                    print_info(&config.position, config.mv_format, &info);
+                match config.protocol {
+                    Protocol::Pretty => {
+                        print_info_pretty(&config.position, config.limits.multi_pv, &info)
+                    }
+                    Protocol::Uci => {
+                        print_info(&config.position, config.mv_format, config.show_wdl, &info)
+                    }
+                    Protocol::Xboard => {
+                        if (config.post || config.limits.infinite) && info.multi_pv == 1 {
+                            xboard::print_thinking(&config.position, config.mv_format, &info);
+                        }
+                    }
                 }
 
-                if info.finished {
-                    match config.mv_format {
-                        MoveFormat::Standard => println!(
-                            "bestmove {}",
-                            display_uci_move(&config.position, info.pv[0])
-                        ),
-                        MoveFormat::Chess960 => println!("bestmove {}", info.pv[0]),
-                    }
+                if info.multi_pv == 1 {
+                    best_pv.clear();
+                    best_pv.extend_from_slice(info.pv);
                 }
 
                 stdout().flush().unwrap();
@@ -502,6 +933,18 @@ fn search_thread(
             }
         };
 
+        let curr_move: &mut dyn FnMut(Move, usize) = match id {
+            0 if config.protocol == Protocol::Uci => &mut |mv, number| {
+                if config.start.elapsed() > Duration::from_secs(3) {
+                    println!(
+                        "info currmove {} currmovenumber {number}",
+                        format_move(&config.position, mv, config.mv_format)
+                    );
+                }
+            },
+            _ => &mut |_, _| {},
+        };
+
         let clock: &dyn Fn() -> Duration = match id {
             0 => &|| config.start.elapsed(),
             _ => &|| Duration::ZERO,
@@ -512,10 +955,65 @@ fn search_thread(
             history: config.history.clone(),
             clock,
             info,
+            curr_move,
             data: &mut local_data,
             shared,
             limits,
         }
-        .search()
+        .search();
+
+        config.thread_nodes[id].store(local_data.nodes(), Ordering::SeqCst);
+        config.running.fetch_sub(1, Ordering::SeqCst);
+
+        if id != 0 {
+            continue;
+        }
+
+        while config.started.load(Ordering::SeqCst) != config.thread_nodes.len()
+            || config.running.load(Ordering::SeqCst) != 0
+        {
+            std::thread::yield_now();
+        }
+
+        if config.protocol == Protocol::Xboard {
+            xboard::report_move(config, best_pv[0]);
+            continue;
+        }
+
+        if config.thread_nodes.len() > 1 {
+            let total: u64 = config
+                .thread_nodes
+                .iter()
+                .map(|n| n.load(Ordering::SeqCst))
+                .sum();
+            for (i, nodes) in config.thread_nodes.iter().enumerate() {
+                let nodes = nodes.load(Ordering::SeqCst);
+                println!(
+                    "info string thread {i} nodes {nodes} ({:.1}%)",
+                    nodes as f64 * 100.0 / total.max(1) as f64
+                );
+            }
+        }
+
+        let best_mv = best_pv[0];
+        let mut board = config.position.clone();
+        board.play(best_mv);
+        let ponder_mv = best_pv
+            .get(1)
+            .copied()
+            .or_else(|| shared.probe_move(&board));
+
+        print!(
+            "bestmove {}",
+            format_move(&config.position, best_mv, config.mv_format)
+        );
+        if let Some(ponder_mv) = ponder_mv {
+            print!(
+                " ponder {}",
+                format_move(&board, ponder_mv, config.mv_format)
+            );
+        }
+        println!();
+        stdout().flush().unwrap();
     }
 }
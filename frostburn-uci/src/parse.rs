@@ -0,0 +1,99 @@
+//! Validation of UCI command arguments.
+//!
+//! Everything here reports malformed input as a message to be sent back as `info string` rather
+//! than panicking, so that callers can leave their state untouched when a command is rejected.
+
+use std::fmt::Display;
+use std::iter::Peekable;
+use std::str::FromStr;
+
+use cozy_chess::util::parse_uci_move;
+use cozy_chess::{Board, Move};
+
+use crate::{MoveFormat, TokenIter};
+
+pub fn check(name: &str, value: Option<&str>) -> Result<bool, String> {
+    match value {
+        Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(v) => Err(format!(
+            "invalid value `{v}` for {name}, expected true or false"
+        )),
+        None => Err(format!("missing value for {name}")),
+    }
+}
+
+pub fn spin<T>(name: &str, value: Option<&str>, min: T, max: T) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + Display,
+{
+    let value = value.ok_or_else(|| format!("missing value for {name}"))?;
+    match value.parse() {
+        Ok(v) if v >= min && v <= max => Ok(v),
+        _ => Err(format!(
+            "invalid value `{value}` for {name}, expected integer in {min}..={max}"
+        )),
+    }
+}
+
+/// Parses the argument of a `go` parameter. GUIs occasionally send slightly negative clock times,
+/// so these are treated as zero.
+pub fn number(verb: &str, token: Option<&str>) -> Result<u64, String> {
+    let token = token.ok_or_else(|| format!("missing value for {verb}"))?;
+    token
+        .parse::<i64>()
+        .map(|v| v.max(0) as u64)
+        .map_err(|_| format!("invalid value `{token}` for {verb}"))
+}
+
+pub fn parse_move(board: &Board, token: &str, mv_format: MoveFormat) -> Result<Move, String> {
+    let mv = match mv_format {
+        MoveFormat::Standard => parse_uci_move(board, token),
+        MoveFormat::Chess960 => token.parse(),
+    }
+    .map_err(|_| format!("invalid move `{token}`"))?;
+
+    match board.is_legal(mv) {
+        true => Ok(mv),
+        false => Err(format!("illegal move `{token}` in position {board}")),
+    }
+}
+
+/// Parses the arguments of a `position` command into the resulting board and the hashes of the
+/// positions leading up to it.
+pub fn position(
+    tokens: &mut Peekable<&mut TokenIter>,
+    mv_format: MoveFormat,
+) -> Result<(Board, Vec<u64>), String> {
+    let mut board = match tokens.next() {
+        Some("startpos") => Board::startpos(),
+        Some("fen") => {
+            let mut fen = tokens
+                .by_ref()
+                .take(4)
+                .fold(String::new(), |s, t| s + t + " ");
+            fen += tokens.next_if(|&tok| tok != "moves").unwrap_or("0");
+            fen += " ";
+            fen += tokens.next_if(|&tok| tok != "moves").unwrap_or("1");
+            fen.trim()
+                .parse()
+                .map_err(|e| format!("invalid fen `{}`: {e}", fen.trim()))?
+        }
+        Some(unknown) => return Err(format!("unknown position type `{unknown}`")),
+        None => return Err("missing position type".to_owned()),
+    };
+
+    let mut history = vec![];
+    match tokens.next() {
+        Some("moves") | None => {}
+        Some(unknown) => return Err(format!("expected `moves`, found `{unknown}`")),
+    }
+
+    for token in tokens {
+        let mv = parse_move(&board, token, mv_format)?;
+        history.push(board.hash());
+        board.play_unchecked(mv);
+    }
+
+    Ok((board, history))
+}
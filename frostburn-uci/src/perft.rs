@@ -0,0 +1,293 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
+use cozy_chess::util::display_uci_move;
+use cozy_chess::{Board, File as BoardFile, Move};
+
+/// Leaf counts for `(position, depth)` pairs, shared between threads without locking. Each entry
+/// stores the key xor'd with the data, so torn writes are rejected by the key check.
+struct PerftTable {
+    entries: Vec<[AtomicU64; 2]>,
+}
+
+impl PerftTable {
+    fn new(mb: usize) -> Self {
+        let len = (mb * 1024 * 1024 / 16).max(1);
+        PerftTable {
+            entries: (0..len)
+                .map(|_| [AtomicU64::new(0), AtomicU64::new(0)])
+                .collect(),
+        }
+    }
+
+    fn index(&self, hash: u64) -> usize {
+        ((hash as u128 * self.entries.len() as u128) >> 64) as usize
+    }
+
+    fn load(&self, hash: u64, depth: u8) -> Option<u64> {
+        let [key, data] = &self.entries[self.index(hash)];
+        let data = data.load(Ordering::Relaxed);
+        let key = key.load(Ordering::Relaxed) ^ data;
+        (key == hash && data as u8 == depth).then_some(data >> 8)
+    }
+
+    fn store(&self, hash: u64, depth: u8, count: u64) {
+        let [key, data] = &self.entries[self.index(hash)];
+        let new = count << 8 | depth as u64;
+        key.store(hash ^ new, Ordering::Relaxed);
+        data.store(new, Ordering::Relaxed);
+    }
+}
+
+/// Counts the leaves below each root move, searching root moves in parallel. Once `abort` is set,
+/// no further root moves are started and the counts are incomplete.
+fn divide(
+    board: &Board,
+    depth: u8,
+    threads: usize,
+    table: Option<&PerftTable>,
+    abort: &AtomicBool,
+) -> Vec<(Move, u64)> {
+    let mut root_moves = vec![];
+    board.generate_moves(|mvs| {
+        root_moves.extend(mvs);
+        false
+    });
+
+    let counts: Vec<_> = root_moves.iter().map(|_| AtomicU64::new(0)).collect();
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        for _ in 0..threads.clamp(1, root_moves.len().max(1)) {
+            s.spawn(|| loop {
+                if abort.load(Ordering::Relaxed) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(&mv) = root_moves.get(i) else {
+                    break;
+                };
+                let mut board = board.clone();
+                board.play_unchecked(mv);
+                counts[i].store(perft(&board, depth - 1, table), Ordering::Relaxed);
+            });
+        }
+    });
+
+    root_moves
+        .into_iter()
+        .zip(counts)
+        .map(|(mv, count)| (mv, count.into_inner()))
+        .collect()
+}
+
+fn perft(board: &Board, depth: u8, table: Option<&PerftTable>) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+
+    let mut count = 0;
+    if depth == 1 {
+        board.generate_moves(|mvs| {
+            count += mvs.len() as u64;
+            false
+        });
+        return count;
+    }
+
+    if let Some(count) = table.and_then(|t| t.load(board.hash(), depth)) {
+        return count;
+    }
+
+    board.generate_moves(|mvs| {
+        for mv in mvs {
+            let mut board = board.clone();
+            board.play_unchecked(mv);
+            count += perft(&board, depth - 1, table);
+        }
+        false
+    });
+
+    if let Some(table) = table {
+        table.store(board.hash(), depth, count);
+    }
+    count
+}
+
+/// Whether castling moves have to be written as king-captures-rook to be unambiguous.
+fn is_chess960(board: &Board) -> bool {
+    [board.side_to_move(), !board.side_to_move()]
+        .into_iter()
+        .any(|color| {
+            let rights = board.castle_rights(color);
+            let castles = rights.short.is_some() || rights.long.is_some();
+            castles
+                && (board.king(color).file() != BoardFile::E
+                    || rights.short.is_some_and(|f| f != BoardFile::H)
+                    || rights.long.is_some_and(|f| f != BoardFile::A))
+        })
+}
+
+fn format_move(board: &Board, mv: Move, chess960: bool) -> String {
+    match chess960 {
+        true => mv.to_string(),
+        false => display_uci_move(board, mv).to_string(),
+    }
+}
+
+/// `go perft N`: prints the leaf count below each root move followed by the total, unless `abort`
+/// is set first.
+pub fn go_perft(board: &Board, depth: u8, threads: usize, chess960: bool, abort: &AtomicBool) {
+    let start = Instant::now();
+    let divided = match depth {
+        0 => vec![],
+        _ => divide(board, depth, threads, None, abort),
+    };
+    if abort.load(Ordering::Relaxed) {
+        println!("info string perft {depth} aborted");
+        return;
+    }
+    let total: u64 = match depth {
+        0 => 1,
+        _ => divided.iter().map(|&(_, count)| count).sum(),
+    };
+
+    for (mv, count) in divided {
+        println!("{}: {count}", format_move(board, mv, chess960));
+    }
+    println!();
+    println!("Nodes searched: {total}");
+    println!(
+        "info string perft {depth} took {:.3}s",
+        start.elapsed().as_secs_f64()
+    );
+}
+
+pub fn run(mut args: impl Iterator<Item = String>) {
+    let usage = || -> ! {
+        eprintln!("usage: perft [--threads N] [--hash MB] [--divide] <depth> [fen]");
+        eprintln!("       perft [--threads N] [--hash MB] [--depth N] --epd <file>");
+        eprintln!("epd lines are `<fen> ;D1 <count> ;D2 <count> ...`");
+        exit(1);
+    };
+
+    let mut threads = 1;
+    let mut hash_mb = 0;
+    let mut show_divide = false;
+    let mut max_depth = u8::MAX;
+    let mut epd = None;
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match &*arg {
+            "--threads" => threads = value().parse().unwrap_or_else(|_| usage()),
+            "--hash" => hash_mb = value().parse().unwrap_or_else(|_| usage()),
+            "--depth" => max_depth = value().parse().unwrap_or_else(|_| usage()),
+            "--epd" => epd = Some(value()),
+            "--divide" => show_divide = true,
+            _ => positional.push(arg),
+        }
+    }
+
+    let table = (hash_mb > 0).then(|| PerftTable::new(hash_mb));
+    let table = table.as_ref();
+
+    let Some(epd) = epd else {
+        let depth = positional.first().and_then(|d| d.parse::<u8>().ok());
+        let Some(depth) = depth.filter(|&d| d > 0) else {
+            usage()
+        };
+        let board = match positional.len() {
+            1 => Board::startpos(),
+            _ => positional[1..].join(" ").parse().unwrap_or_else(|e| {
+                eprintln!("invalid fen: {e}");
+                exit(1);
+            }),
+        };
+
+        let start = Instant::now();
+        let divided = divide(&board, depth, threads, table, &AtomicBool::new(false));
+        let total: u64 = divided.iter().map(|&(_, count)| count).sum();
+        if show_divide {
+            let chess960 = is_chess960(&board);
+            for (mv, count) in divided {
+                println!("{}: {count}", format_move(&board, mv, chess960));
+            }
+            println!();
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "{total} nodes {:.3}s {} nps",
+            secs,
+            (total as f64 / secs) as u64
+        );
+        return;
+    };
+
+    let file = File::open(&epd).unwrap_or_else(|e| {
+        eprintln!("failed to open {epd}: {e}");
+        exit(1);
+    });
+
+    let start = Instant::now();
+    let mut total_nodes = 0;
+    let mut passed = 0;
+    let mut checked = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.unwrap_or_else(|e| {
+            eprintln!("failed to read {epd}: {e}");
+            exit(1);
+        });
+        let mut fields = line.split(';');
+        let fen = fields.next().unwrap_or("").trim();
+        if fen.is_empty() {
+            continue;
+        }
+        let board: Board = match fen.parse() {
+            Ok(board) => board,
+            Err(e) => {
+                println!("FAIL invalid fen ({e}): {fen}");
+                checked += 1;
+                continue;
+            }
+        };
+
+        for field in fields {
+            let mut parts = field.split_ascii_whitespace();
+            let depth = parts
+                .next()
+                .and_then(|d| d.strip_prefix('D')?.parse::<u8>().ok());
+            let expected = parts.next().and_then(|c| c.parse::<u64>().ok());
+            let (Some(depth), Some(expected)) = (depth, expected) else {
+                continue;
+            };
+            if depth == 0 || depth > max_depth {
+                continue;
+            }
+
+            let count: u64 = divide(&board, depth, threads, table, &AtomicBool::new(false))
+                .iter()
+                .map(|&(_, count)| count)
+                .sum();
+            total_nodes += count;
+            checked += 1;
+            if count == expected {
+                passed += 1;
+                println!("ok   D{depth} {count:>12} {fen}");
+            } else {
+                println!("FAIL D{depth} {count:>12} (expected {expected}) {fen}");
+            }
+        }
+    }
+
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "{passed}/{checked} passed, {total_nodes} nodes {secs:.3}s {} nps",
+        (total_nodes as f64 / secs) as u64
+    );
+    if passed != checked {
+        exit(1);
+    }
+}
@@ -62,12 +62,13 @@ pub fn reproduce(side: Color, mb: usize) {
                 history: history.clone(),
                 clock: &|| start.elapsed(),
                 info: &mut |info| {
-                    super::print_info(&board, MoveFormat::Chess960, &info);
+                    super::print_info(&board, MoveFormat::Chess960, false, &info);
                     if info.finished {
                         assert_eq!(info.nodes, nodes);
                         assert_eq!(info.pv[0], mv);
                     }
                 },
+                curr_move: &mut |_, _| {},
                 data: &mut local,
                 shared: &shared,
                 limits: Limits {
@@ -0,0 +1,588 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::prelude::Write;
+use std::io::stdout;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use cozy_chess::{Board, Color, GameStatus, Piece};
+use datafmt::Header;
+use frostburn::{Eval, Limits, LocalData, NnueBackend, NnueNetwork, Search, SharedData};
+
+use crate::bench::parse_position;
+
+/// Scores beyond this which both engines agree on end the game.
+const RESIGN_SCORE: i16 = 1000;
+/// Games are drawn once both engines report scores within this many centipawns for
+/// `DRAW_PLIES` plies, after `DRAW_MIN_PLY`.
+const DRAW_SCORE: i16 = 10;
+const DRAW_PLIES: usize = 8;
+const DRAW_MIN_PLY: usize = 80;
+
+/// Player configuration. Everything except the tunables is private to the engine, so the tunables
+/// are the only reason games can't be played concurrently.
+pub struct Engine {
+    name: String,
+    limits: Limits,
+    /// Base time and increment, if playing with a clock.
+    time_control: Option<(Duration, Duration)>,
+    hash_mb: usize,
+    backend: NnueBackend,
+    network: NnueNetwork,
+    #[cfg(feature = "tunable")]
+    tunables: Vec<(&'static frostburn::Tunable, i16)>,
+}
+
+impl Engine {
+    /// An engine with default settings searching with `limits`.
+    pub fn new(name: &str, limits: Limits) -> Self {
+        Engine {
+            name: name.to_owned(),
+            limits,
+            time_control: None,
+            hash_mb: 16,
+            backend: NnueBackend::default(),
+            network: NnueNetwork::embedded(),
+            #[cfg(feature = "tunable")]
+            tunables: vec![],
+        }
+    }
+
+    /// Parses `key=value,...`. Keys are `name`, `nodes`, `depth`, `movetime` (ms), `tc` (seconds,
+    /// `base+inc`), `elo`, `hash`, `backend`, `network` and, with the tunable feature, parameter
+    /// names.
+    fn parse(name: &str, spec: &str) -> Result<Self, String> {
+        let mut engine = Engine::new(name, Limits::default());
+        let mut elo = None;
+
+        for option in spec.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, found `{option}`"))?;
+            let invalid = || format!("invalid value `{value}` for {key}");
+            match key {
+                "name" => engine.name = value.to_owned(),
+                "nodes" => engine.limits.nodes = Some(value.parse().map_err(|_| invalid())?),
+                "depth" => engine.limits.depth = Some(value.parse().map_err(|_| invalid())?),
+                "movetime" => {
+                    let ms = value.parse().map_err(|_| invalid())?;
+                    engine.limits.move_time = Some(Duration::from_millis(ms));
+                }
+                "tc" => {
+                    let (base, inc) = value.split_once('+').unwrap_or((value, "0"));
+                    let seconds = |s: &str| {
+                        s.parse()
+                            .ok()
+                            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                    };
+                    let (Some(base), Some(inc)) = (seconds(base), seconds(inc)) else {
+                        return Err(invalid());
+                    };
+                    engine.time_control = Some((base, inc));
+                }
+                "elo" => elo = Some(value.parse().map_err(|_| invalid())?),
+                "hash" => engine.hash_mb = value.parse().map_err(|_| invalid())?,
+                "backend" => {
+                    engine.backend = NnueBackend::available()
+                        .into_iter()
+                        .find(|backend| backend.name() == value)
+                        .ok_or_else(|| format!("unavailable NNUE backend `{value}`"))?;
+                }
+                "network" => {
+                    let bytes = std::fs::read(value)
+                        .map_err(|e| format!("failed to read network `{value}`: {e}"))?;
+                    engine.network = NnueNetwork::from_bytes(&bytes)
+                        .map_err(|e| format!("invalid network `{value}`: {e}"))?;
+                }
+                #[cfg(feature = "tunable")]
+                param => {
+                    let tunable = frostburn::TUNABLES
+                        .iter()
+                        .find(|tunable| tunable.name == param)
+                        .ok_or_else(|| format!("unknown option `{key}`"))?;
+                    let v = crate::parse::spin(key, Some(value), tunable.min, tunable.max)?;
+                    engine.tunables.push((tunable, v));
+                }
+                #[allow(
+                    unreachable_patterns,
+                    reason = "suppress warning when tunable feature enabled"
+                )]
+                _ => return Err(format!("unknown option `{key}`")),
+            }
+        }
+
+        if let Some(elo) = elo {
+            crate::strength::limit_strength(&mut engine.limits, elo);
+        }
+        let limited = engine.limits.nodes.is_some()
+            || engine.limits.depth.is_some()
+            || engine.limits.move_time.is_some();
+        if !limited && engine.time_control.is_none() {
+            engine.time_control = Some((Duration::from_secs(10), Duration::from_millis(100)));
+        }
+        Ok(engine)
+    }
+
+    fn has_tunables(&self) -> bool {
+        #[cfg(feature = "tunable")]
+        return !self.tunables.is_empty();
+        #[cfg(not(feature = "tunable"))]
+        false
+    }
+
+    /// Stores this engine's parameters, restoring the defaults for ones it doesn't set.
+    fn apply_tunables(&self) {
+        #[cfg(feature = "tunable")]
+        for tunable in frostburn::TUNABLES {
+            let value = self
+                .tunables
+                .iter()
+                .find(|(t, _)| t.name == tunable.name)
+                .map_or(tunable.default, |&(_, v)| v);
+            tunable.atomic.store(value, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Search state of one engine, owned by the thread playing the game.
+struct Player {
+    shared: SharedData,
+    local: LocalData,
+}
+
+impl Player {
+    fn new(engine: &Engine) -> Self {
+        let mut shared = SharedData::new(engine.hash_mb);
+        shared.nnue_backend = engine.backend;
+        shared.nnue_network = engine.network.clone();
+        Player {
+            shared,
+            local: LocalData::new(),
+        }
+    }
+
+    fn new_game(&mut self) {
+        for block in self.shared.get_clear_tt_blocks(1) {
+            self.shared.clear_tt_block(block);
+        }
+        self.local = LocalData::new();
+        // vary the noise of strength limited engines between games
+        self.shared.seed = Rng::new().next();
+    }
+}
+
+/// splitmix64, seeded from the OS.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        Rng(RandomState::new().build_hasher().finish())
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+/// A random double Chess960 position followed by 8 or 9 random moves, rejecting positions where
+/// the game is over or the material is unbalanced by 3 or more pawns. This matches the openings
+/// datagen plays.
+fn dfrc_opening(rng: &mut Rng) -> Board {
+    const MATERIAL: [u32; 6] = [1, 3, 3, 5, 9, 0];
+    let material = |board: &Board, color| -> u32 {
+        Piece::ALL
+            .iter()
+            .map(|&piece| MATERIAL[piece as usize] * board.colored_pieces(color, piece).len())
+            .sum()
+    };
+
+    let mut moves = vec![];
+    'retry: loop {
+        let white = rng.below(960) as u32;
+        let black = rng.below(960) as u32;
+        let mut board = Board::double_chess960_startpos(white, black);
+        if rng.below(2) == 1 {
+            board = board.null_move().unwrap();
+        }
+
+        for _ in 0..8 + rng.below(2) {
+            moves.clear();
+            board.generate_moves(|mvs| {
+                moves.extend(mvs);
+                false
+            });
+            if moves.is_empty() {
+                continue 'retry;
+            }
+            board.play_unchecked(moves[rng.below(moves.len() as u64) as usize]);
+        }
+
+        if board.status() != GameStatus::Ongoing {
+            continue 'retry;
+        }
+        if material(&board, Color::White).abs_diff(material(&board, Color::Black)) >= 3 {
+            continue 'retry;
+        }
+        return board;
+    }
+}
+
+/// Plays one game and returns the index of the winning engine.
+fn play_game(
+    engines: &[Engine; 2],
+    players: &mut [Player],
+    opening: &Board,
+    first_white: bool,
+) -> Option<usize> {
+    let concurrent_tunables = engines.iter().any(Engine::has_tunables);
+    for player in players.iter_mut() {
+        player.new_game();
+    }
+
+    let mut board = opening.clone();
+    let mut history = vec![];
+    let mut clocks = engines
+        .each_ref()
+        .map(|e| e.time_control.map(|(base, _)| base));
+    let mut last_score = [None; 2];
+    let mut quiet_plies = 0;
+
+    for ply in 0.. {
+        let idx = (board.side_to_move() == Color::White) as usize ^ first_white as usize;
+        let (engine, player) = (&engines[idx], &mut players[idx]);
+        if concurrent_tunables {
+            engine.apply_tunables();
+        }
+
+        let mut limits = engine.limits.clone();
+        if let Some((_, inc)) = engine.time_control {
+            limits.clock = clocks[idx];
+            limits.increment = inc;
+        }
+
+        let mut result = None;
+        player.shared.prepare_for_search();
+        let start = Instant::now();
+        Search {
+            root: &board,
+            history: history.clone(),
+            clock: &|| start.elapsed(),
+            info: &mut |info| {
+                if info.multi_pv == 1 {
+                    result = info.pv.first().map(|&mv| (mv, info.score));
+                }
+            },
+            curr_move: &mut |_, _| {},
+            data: &mut player.local,
+            shared: &player.shared,
+            limits,
+        }
+        .search();
+
+        if let (Some(clock), Some((_, inc))) = (&mut clocks[idx], engine.time_control) {
+            let Some(remaining) = clock.checked_sub(start.elapsed()) else {
+                return Some(1 - idx);
+            };
+            *clock = remaining + inc;
+        }
+
+        let (mv, score) = result.unwrap_or_else(|| panic!("no move in position {board}"));
+        history.push(board.hash());
+        board.play_unchecked(mv);
+
+        match board.status() {
+            GameStatus::Won => return Some(idx),
+            GameStatus::Drawn => return None,
+            GameStatus::Ongoing => {}
+        }
+        if history.iter().filter(|&&h| h == board.hash()).count() >= 2 {
+            return None;
+        }
+
+        // adjudicate once both engines agree on the result
+        last_score[idx] = Some(score);
+        if let [Some(own), Some(other)] = [last_score[idx], last_score[1 - idx]] {
+            if own >= Eval::cp(RESIGN_SCORE) && other <= Eval::cp(-RESIGN_SCORE) {
+                return Some(idx);
+            }
+            if own <= Eval::cp(-RESIGN_SCORE) && other >= Eval::cp(RESIGN_SCORE) {
+                return Some(1 - idx);
+            }
+        }
+        match score.clamp_nonmate() == score
+            && score >= Eval::cp(-DRAW_SCORE)
+            && score <= Eval::cp(DRAW_SCORE)
+        {
+            true => quiet_plies += 1,
+            false => quiet_plies = 0,
+        }
+        if ply >= DRAW_MIN_PLY && quiet_plies >= DRAW_PLIES {
+            return None;
+        }
+    }
+    unreachable!()
+}
+
+fn erf(x: f64) -> f64 {
+    // Abramowitz and Stegun 7.1.26
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1.0 - poly * (-x * x).exp()).copysign(x)
+}
+
+/// Likelihood of superiority of the first engine.
+fn los(header: &Header) -> f64 {
+    let (w, l) = (header.wins as f64, header.losses as f64);
+    match w + l {
+        0.0 => 0.5,
+        n => 0.5 * (1.0 + erf((w - l) / (2.0 * n).sqrt())),
+    }
+}
+
+/// Log-likelihood ratio of logistic elo `elo1` against `elo0`, using the normal approximation of
+/// the trinomial game results.
+fn llr(header: &Header, elo0: f64, elo1: f64) -> f64 {
+    let n = header.count() as f64;
+    if n == 0.0 {
+        return 0.0;
+    }
+    let (w, d, l) = (
+        header.wins as f64,
+        header.draws as f64,
+        header.losses as f64,
+    );
+    let mu = (w + 0.5 * d) / n;
+    let var = (w * (1.0 - mu).powi(2) + d * (0.5 - mu).powi(2) + l * mu.powi(2)) / n;
+    if var == 0.0 {
+        return 0.0;
+    }
+    let score = |elo: f64| 1.0 / (1.0 + 10f64.powf(-elo / 400.0));
+    let (s0, s1) = (score(elo0), score(elo1));
+    n * (s1 - s0) * (2.0 * mu - s0 - s1) / (2.0 * var)
+}
+
+struct Sprt {
+    elo0: f64,
+    elo1: f64,
+    lower: f64,
+    upper: f64,
+}
+
+struct Progress {
+    header: Header,
+    started: usize,
+    openings: Vec<Board>,
+    rng: Rng,
+}
+
+fn report(header: &Header, engines: &[Engine; 2], sprt: Option<&Sprt>) -> Option<&'static str> {
+    print!(
+        "\r{} vs {}: {} games  W {} L {} D {}",
+        engines[0].name,
+        engines[1].name,
+        header.count(),
+        header.wins,
+        header.losses,
+        header.draws
+    );
+    match header.elo() {
+        Some((elo, conf)) => print!("  Elo: {elo:>6.2} +- {conf:<6.2}"),
+        None => print!("  Elo: {:<16}", "-"),
+    }
+    print!("  LOS: {:>5.1}%", los(header) * 100.0);
+
+    let mut verdict = None;
+    if let Some(sprt) = sprt {
+        let llr = llr(header, sprt.elo0, sprt.elo1);
+        print!(
+            "  LLR: {llr:>5.2} ({:.2}, {:.2}) [{}, {}]",
+            sprt.lower, sprt.upper, sprt.elo0, sprt.elo1
+        );
+        if llr >= sprt.upper {
+            verdict = Some("H1 accepted");
+        } else if llr <= sprt.lower {
+            verdict = Some("H0 accepted");
+        }
+    }
+    print!("  ");
+    stdout().flush().unwrap();
+    verdict
+}
+
+/// `match [options] <engine> <engine>`: plays games between two configurations of the engine.
+pub fn run(mut args: impl Iterator<Item = String>) {
+    let usage = || -> ! {
+        eprintln!(
+            "usage: match [--games N] [--concurrency N] [--openings dfrc|FILE] \
+             [--sprt ELO0,ELO1] [--alpha A] [--beta B] <engine> <engine>"
+        );
+        eprintln!("engines are comma-separated key=value lists, which may be empty. keys are:");
+        eprintln!("  name, nodes, depth, movetime (ms), tc (seconds, base+inc), elo, hash (MB),");
+        eprintln!("  backend, network, and tunable parameters when built with them");
+        eprintln!("engines play with tc=10+0.1 unless limited otherwise");
+        eprintln!("openings are played once with each color");
+        exit(1);
+    };
+
+    let mut games = 1000;
+    let mut concurrency = 1;
+    let mut openings = None;
+    let mut sprt_bounds = None;
+    let (mut alpha, mut beta) = (0.05f64, 0.05f64);
+    let mut specs = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match &*arg {
+            "--games" => games = value().parse().unwrap_or_else(|_| usage()),
+            "--concurrency" => concurrency = value().parse().unwrap_or_else(|_| usage()),
+            "--openings" => {
+                openings = match &*value() {
+                    "dfrc" => None,
+                    path => Some(read_openings(path)),
+                }
+            }
+            "--sprt" => {
+                let value = value();
+                let bounds = value.split_once(',').and_then(|(elo0, elo1)| {
+                    Some((elo0.parse::<f64>().ok()?, elo1.parse::<f64>().ok()?))
+                });
+                sprt_bounds = Some(bounds.unwrap_or_else(|| usage()));
+            }
+            "--alpha" => alpha = value().parse().unwrap_or_else(|_| usage()),
+            "--beta" => beta = value().parse().unwrap_or_else(|_| usage()),
+            _ if arg.starts_with("--") => usage(),
+            _ => specs.push(arg),
+        }
+    }
+    if specs.len() != 2 || concurrency == 0 || games == 0 {
+        usage();
+    }
+
+    let engines = [("A", &specs[0]), ("B", &specs[1])].map(|(name, spec)| {
+        Engine::parse(name, spec).unwrap_or_else(|e| {
+            eprintln!("engine {name}: {e}");
+            exit(1);
+        })
+    });
+    if concurrency > 1 && engines.iter().any(Engine::has_tunables) {
+        eprintln!("tunable parameters are global, so games are played one at a time");
+        concurrency = 1;
+    }
+    let sprt = sprt_bounds.map(|(elo0, elo1)| Sprt {
+        elo0,
+        elo1,
+        lower: (beta / (1.0 - alpha)).ln(),
+        upper: ((1.0 - beta) / alpha).ln(),
+    });
+
+    let verdict = run_games(&engines, games, concurrency, openings, sprt.as_ref()).1;
+    if let Some(verdict) = verdict {
+        println!("SPRT: {verdict}");
+    }
+}
+
+/// Plays `games` games between `engines` from random DFRC openings and returns the results of the
+/// first engine.
+pub fn play_match(engines: &[Engine; 2], games: usize, concurrency: usize) -> Header {
+    run_games(engines, games, concurrency, None, None).0
+}
+
+/// Plays until `games` games are finished or the SPRT reaches a verdict, printing progress.
+fn run_games(
+    engines: &[Engine; 2],
+    games: usize,
+    concurrency: usize,
+    openings: Option<Vec<Board>>,
+    sprt: Option<&Sprt>,
+) -> (Header, Option<&'static str>) {
+    let progress = Mutex::new(Progress {
+        header: Header {
+            wins: 0,
+            losses: 0,
+            draws: 0,
+            nominal_positions: 0,
+        },
+        started: 0,
+        openings: openings.unwrap_or_default(),
+        rng: Rng::new(),
+    });
+    let dfrc = progress.lock().unwrap().openings.is_empty();
+    let stop = AtomicBool::new(false);
+    let verdict = Mutex::new(None);
+
+    std::thread::scope(|s| {
+        for _ in 0..concurrency {
+            s.spawn(|| {
+                // search data is too large to keep two copies on the stack
+                let mut players: Vec<_> = engines.iter().map(Player::new).collect();
+                loop {
+                    let mut guard = progress.lock().unwrap();
+                    if guard.started == games || stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let game = guard.started;
+                    guard.started += 1;
+                    // both games of a pair share the opening, with colors swapped
+                    if dfrc && game.is_multiple_of(2) {
+                        let board = dfrc_opening(&mut guard.rng);
+                        guard.openings.push(board);
+                    }
+                    let opening = guard.openings[game / 2 % guard.openings.len()].clone();
+                    drop(guard);
+
+                    let winner = play_game(engines, &mut players, &opening, game.is_multiple_of(2));
+
+                    let mut guard = progress.lock().unwrap();
+                    match winner {
+                        Some(0) => guard.header.wins += 1,
+                        Some(_) => guard.header.losses += 1,
+                        None => guard.header.draws += 1,
+                    }
+                    if let Some(result) = report(&guard.header, engines, sprt) {
+                        verdict.lock().unwrap().get_or_insert(result);
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                }
+            });
+        }
+    });
+    println!();
+
+    let header = progress.into_inner().unwrap().header;
+    (header, verdict.into_inner().unwrap())
+}
+
+fn read_openings(path: &str) -> Vec<Board> {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("failed to read {path}: {e}");
+        exit(1);
+    });
+    let openings: Vec<_> = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            parse_position(line).unwrap_or_else(|e| {
+                eprintln!("{path}:{}: {e}", i + 1);
+                exit(1);
+            })
+        })
+        .collect();
+    if openings.is_empty() {
+        eprintln!("no openings in {path}");
+        exit(1);
+    }
+    openings
+}
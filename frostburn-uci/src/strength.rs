@@ -0,0 +1,113 @@
+use std::process::exit;
+
+use frostburn::Limits;
+
+use crate::selfplay::{play_match, Engine};
+
+/// (elo, nodes, eval noise, root noise)
+///
+/// The Elo values come from `frostburn-uci calibrate`, which plays each level against the next
+/// stronger one and chains the measured differences down from the full strength level at the
+/// bottom of the table. Only the differences are measured; the absolute scale is fixed by
+/// anchoring the last level at 2450. The values below were measured with 200 games per step, so
+/// each difference is only good to about 45 Elo. Rerun it after changes which affect playing
+/// strength and paste the table it prints here.
+const LEVELS: &[(i32, u64, i16, i16)] = &[
+    (708, 250, 100, 200),
+    (906, 250, 0, 100),
+    (941, 250, 0, 50),
+    (1163, 250, 0, 0),
+    (1356, 500, 0, 0),
+    (1540, 1000, 0, 0),
+    (1731, 2000, 0, 0),
+    (1900, 4000, 0, 0),
+    (2045, 8000, 0, 0),
+    (2176, 16000, 0, 0),
+    (2346, 32000, 0, 0),
+    (2450, 64000, 0, 0),
+];
+
+pub const MIN_ELO: i32 = LEVELS[0].0;
+pub const MAX_ELO: i32 = LEVELS[LEVELS.len() - 1].0;
+
+pub fn limit_strength(limits: &mut Limits, elo: i32) {
+    let elo = elo.clamp(MIN_ELO, MAX_ELO);
+    let i = LEVELS.windows(2).position(|w| elo <= w[1].0).unwrap();
+    let (lo_elo, lo_nodes, lo_eval, lo_root) = LEVELS[i];
+    let (hi_elo, hi_nodes, hi_eval, hi_root) = LEVELS[i + 1];
+
+    let t = (elo - lo_elo) as f64 / (hi_elo - lo_elo) as f64;
+    let lerp = |a: f64, b: f64| a + (b - a) * t;
+
+    let nodes = lerp((lo_nodes as f64).ln(), (hi_nodes as f64).ln()).exp() as u64;
+    limit_to(
+        limits,
+        nodes,
+        lerp(lo_eval as f64, hi_eval as f64).round() as i16,
+        lerp(lo_root as f64, hi_root as f64).round() as i16,
+    );
+}
+
+fn limit_to(limits: &mut Limits, nodes: u64, eval_noise: i16, root_noise: i16) {
+    limits.min_nodes = Some(limits.min_nodes.map_or(nodes, |n| n.min(nodes)));
+    limits.nodes = Some(limits.nodes.map_or(4 * nodes, |n| n.min(4 * nodes)));
+    limits.eval_noise = eval_noise;
+    limits.root_noise = root_noise;
+}
+
+/// `calibrate [--games N] [--concurrency N]`: measures the Elo of each level by playing it against
+/// the next stronger level, and prints the resulting table.
+pub fn run(mut args: impl Iterator<Item = String>) {
+    let usage = || -> ! {
+        eprintln!("usage: calibrate [--games N] [--concurrency N]");
+        eprintln!("plays N games between each pair of adjacent levels");
+        exit(1);
+    };
+
+    let mut games = 400;
+    let mut concurrency = 1;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match &*arg {
+            "--games" => games = value().parse().unwrap_or_else(|_| usage()),
+            "--concurrency" => concurrency = value().parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+    if concurrency == 0 || games == 0 {
+        usage();
+    }
+
+    let engine = |i: usize| {
+        let (_, nodes, eval_noise, root_noise) = LEVELS[i];
+        let mut limits = Limits::default();
+        limit_to(&mut limits, nodes, eval_noise, root_noise);
+        Engine::new(&format!("level {i}"), limits)
+    };
+
+    let mut elo = vec![0.0; LEVELS.len()];
+    elo[LEVELS.len() - 1] = MAX_ELO as f64;
+    for i in (0..LEVELS.len() - 1).rev() {
+        let header = play_match(&[engine(i), engine(i + 1)], games, concurrency);
+        let Some((diff, _)) = header.elo() else {
+            eprintln!(
+                "level {i} needs decisive games and draws against level {}",
+                i + 1
+            );
+            exit(1);
+        };
+        elo[i] = elo[i + 1] + diff;
+        if diff >= 0.0 {
+            eprintln!("warning: level {i} is not weaker than level {}", i + 1);
+        }
+    }
+
+    println!("const LEVELS: &[(i32, u64, i16, i16)] = &[");
+    for (&(_, nodes, eval_noise, root_noise), elo) in LEVELS.iter().zip(elo) {
+        println!(
+            "    ({}, {nodes}, {eval_noise}, {root_noise}),",
+            elo.round()
+        );
+    }
+    println!("];");
+}
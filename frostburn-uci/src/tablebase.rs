@@ -0,0 +1,67 @@
+use std::path::Path;
+use std::process::exit;
+use std::time::Instant;
+
+use frostburn::{Table, Tablebase};
+
+const EXTENSION: &str = "fbtb";
+
+/// Loads every table found in `dir`. Missing tables are skipped, since the tablebase is only
+/// consulted for positions whose table is present.
+pub fn load(dir: &str) -> Result<Tablebase, String> {
+    let mut tablebase = Tablebase::default();
+    for name in Tablebase::table_names() {
+        let path = Path::new(dir).join(format!("{name}.{EXTENSION}"));
+        if !path.exists() {
+            continue;
+        }
+        let bytes =
+            std::fs::read(&path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+        let table = Table::from_bytes(&name, &bytes)
+            .map_err(|e| format!("invalid table {}: {e}", path.display()))?;
+        tablebase.add(table);
+    }
+    Ok(tablebase)
+}
+
+/// `tbgen <dir>`: generates every table which isn't in `dir` yet.
+pub fn run(mut args: impl Iterator<Item = String>) {
+    let Some(dir) = args.next() else {
+        eprintln!("usage: tbgen <dir>");
+        exit(1);
+    };
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        eprintln!("failed to create {dir}: {e}");
+        exit(1);
+    }
+
+    let mut tablebase = load(&dir).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        exit(1);
+    });
+    let start = Instant::now();
+    for name in Tablebase::table_names() {
+        let path = Path::new(&dir).join(format!("{name}.{EXTENSION}"));
+        if path.exists() {
+            println!("{name:<8} exists");
+            continue;
+        }
+
+        let table_start = Instant::now();
+        let table = tablebase.generate(&name).unwrap_or_else(|e| {
+            eprintln!("failed to generate {name}: {e}");
+            exit(1);
+        });
+        if let Err(e) = std::fs::write(&path, table.to_bytes()) {
+            eprintln!("failed to write {}: {e}", path.display());
+            exit(1);
+        }
+        println!("{name:<8} {:.1}s", table_start.elapsed().as_secs_f64());
+        tablebase.add(table);
+    }
+    println!(
+        "generated {} tables in {:.1}s",
+        tablebase.len(),
+        start.elapsed().as_secs_f64()
+    );
+}
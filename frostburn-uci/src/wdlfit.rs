@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::Write;
+use std::io::stdout;
+use std::process::exit;
+use std::time::Duration;
+
+use cozy_chess::{Board, Piece};
+use datafmt::DataReader;
+use frostburn::{Eval, Limits, LocalData, Search, SharedData};
+
+/// Scores are grouped into bins this many centipawns wide.
+const SCORE_STEP: i32 = 5;
+/// Materials with fewer positions than this are left out of the polynomial fit.
+const MIN_POSITIONS: u64 = 200;
+
+/// Material as counted by `Eval::wdl`, normalized to 58 at the starting position.
+fn material(board: &Board) -> u32 {
+    let count = |piece| board.pieces(piece).len();
+    let material = count(Piece::Pawn)
+        + 3 * count(Piece::Knight)
+        + 3 * count(Piece::Bishop)
+        + 5 * count(Piece::Rook)
+        + 9 * count(Piece::Queen);
+    material.clamp(17, 78)
+}
+
+/// Probability of a win with `score` under the logistic model, as in `win_rate`.
+fn win_rate(score: f64, a: f64, b: f64) -> f64 {
+    1.0 / (1.0 + ((a - score) / b).exp())
+}
+
+/// Maximum likelihood fit of the model's `a` and `b` to `(score, [wins, draws, losses])` bins.
+fn fit_ab(bins: &[(f64, [f64; 3])]) -> (f64, f64) {
+    let total: f64 = bins.iter().map(|(_, c)| c.iter().sum::<f64>()).sum();
+    let mut params = [100.0, 100.0];
+    // Adam
+    let (mut m, mut v) = ([0.0; 2], [0.0; 2]);
+    for t in 1..=5000 {
+        let [a, b] = params;
+        let mut grad = [0.0; 2];
+        for &(score, [w, d, l]) in bins {
+            let pw = win_rate(score, a, b);
+            let pl = win_rate(-score, a, b);
+            let pd = (1.0 - pw - pl).max(1e-9);
+            let dw = [
+                -pw * (1.0 - pw) / b,
+                -pw * (1.0 - pw) * (score - a) / (b * b),
+            ];
+            let dl = [
+                -pl * (1.0 - pl) / b,
+                -pl * (1.0 - pl) * (-score - a) / (b * b),
+            ];
+            for i in 0..2 {
+                grad[i] -= w / pw * dw[i] + l / pl * dl[i] - d / pd * (dw[i] + dl[i]);
+            }
+        }
+        for i in 0..2 {
+            let g = grad[i] / total;
+            m[i] = 0.9 * m[i] + 0.1 * g;
+            v[i] = 0.999 * v[i] + 0.001 * g * g;
+            let m_hat = m[i] / (1.0 - 0.9f64.powi(t));
+            let v_hat = v[i] / (1.0 - 0.999f64.powi(t));
+            params[i] -= 0.5 * m_hat / (v_hat.sqrt() + 1e-8);
+        }
+        params[1] = params[1].max(1.0);
+    }
+    (params[0], params[1])
+}
+
+/// Weighted least squares fit of a cubic to `(x, y, weight)` points, highest power first.
+fn fit_cubic(points: &[(f64, f64, f64)]) -> [f64; 4] {
+    let mut system = [[0.0; 5]; 4];
+    for &(x, y, weight) in points {
+        let powers = [x * x * x, x * x, x, 1.0];
+        for i in 0..4 {
+            for j in 0..4 {
+                system[i][j] += weight * powers[i] * powers[j];
+            }
+            system[i][4] += weight * powers[i] * y;
+        }
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..4 {
+        let pivot = (col..4)
+            .max_by(|&i, &j| system[i][col].abs().total_cmp(&system[j][col].abs()))
+            .unwrap();
+        system.swap(col, pivot);
+        let pivot_row = system[col];
+        for row in &mut system[col + 1..] {
+            let factor = row[col] / pivot_row[col];
+            for (x, p) in row.iter_mut().zip(pivot_row).skip(col) {
+                *x -= factor * p;
+            }
+        }
+    }
+    let mut coefficients = [0.0; 4];
+    for row in (0..4).rev() {
+        let rest: f64 = (row + 1..4).map(|k| system[row][k] * coefficients[k]).sum();
+        coefficients[row] = (system[row][4] - rest) / system[row][row];
+    }
+    coefficients
+}
+
+/// `wdlfit [--nodes N] [--every N] <data>`: fits the coefficients of the win rate model used for
+/// `UCI_ShowWDL` to games written by datagen, printing them in the form used in `eval.rs`.
+pub fn run(mut args: impl Iterator<Item = String>) {
+    let usage = || -> ! {
+        eprintln!("usage: wdlfit [--nodes N] [--every N] <data>");
+        eprintln!("every Nth position after the opening moves is scored with an N node search");
+        exit(1);
+    };
+
+    let mut nodes = 5000;
+    let mut every = 4;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match &*arg {
+            "--nodes" => nodes = value().parse().unwrap_or_else(|_| usage()),
+            "--every" => every = value().parse().unwrap_or_else(|_| usage()),
+            _ if arg.starts_with("--") || path.is_some() => usage(),
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path.filter(|_| every > 0) else {
+        usage()
+    };
+
+    let mut reader = File::open(&path)
+        .and_then(DataReader::new)
+        .unwrap_or_else(|e| {
+            eprintln!("failed to open {path}: {e}");
+            exit(1);
+        });
+    let games = reader.header().count();
+
+    let mut shared = SharedData::new(16);
+    let mut data = LocalData::new();
+    // (material, score bin) -> [wins, draws, losses] for the side to move
+    let mut counts: BTreeMap<(u32, i32), [u64; 3]> = BTreeMap::new();
+    let mut positions = 0;
+    let mut game_number = 0;
+    while let Some(game) = reader.read_game().unwrap_or_else(|e| {
+        eprintln!("failed to read {path}: {e}");
+        exit(1);
+    }) {
+        let mut board = Board::double_chess960_startpos(
+            game.white_scharnagl as u32,
+            game.black_scharnagl as u32,
+        );
+        if game.color_flipped {
+            board = board.null_move().unwrap();
+        }
+
+        let mut history = vec![];
+        for (ply, &mv) in game.moves.iter().enumerate() {
+            let scored = ply
+                .checked_sub(game.fake_moves as usize)
+                .is_some_and(|n| n % every == 0);
+            if scored {
+                let mut score = None;
+                shared.prepare_for_search();
+                Search {
+                    root: &board,
+                    history: history.clone(),
+                    clock: &|| Duration::ZERO,
+                    info: &mut |info| {
+                        if info.multi_pv == 1 {
+                            score = Some(info.score);
+                        }
+                    },
+                    curr_move: &mut |_, _| {},
+                    data: &mut data,
+                    shared: &shared,
+                    limits: Limits {
+                        nodes: Some(nodes),
+                        ..Default::default()
+                    },
+                }
+                .search();
+
+                // mates and tablebase results don't use the model
+                let score = score.map(|s| s - Eval::cp(0));
+                if let Some(score) = score.filter(|s| s.abs() < 5000) {
+                    let result = match game.winner {
+                        Some(winner) if winner == board.side_to_move() => 0,
+                        Some(_) => 2,
+                        None => 1,
+                    };
+                    let bin = score.div_euclid(SCORE_STEP);
+                    counts.entry((material(&board), bin)).or_default()[result] += 1;
+                    positions += 1;
+                }
+            }
+            history.push(board.hash());
+            board.play(mv);
+        }
+
+        game_number += 1;
+        print!("\r{game_number}/{games} games  {positions} positions");
+        stdout().flush().unwrap();
+    }
+    println!();
+
+    let mut a_points = vec![];
+    let mut b_points = vec![];
+    let mut materials: Vec<u32> = counts.keys().map(|&(m, _)| m).collect();
+    materials.dedup();
+    for material in materials {
+        let bins: Vec<_> = counts
+            .range((material, i32::MIN)..=(material, i32::MAX))
+            .map(|(&(_, bin), c)| {
+                let score = (bin * SCORE_STEP) as f64 + SCORE_STEP as f64 / 2.0;
+                (score, c.map(|n| n as f64))
+            })
+            .collect();
+        let n: f64 = bins.iter().map(|(_, c)| c.iter().sum::<f64>()).sum();
+        if n < MIN_POSITIONS as f64 {
+            continue;
+        }
+        let (a, b) = fit_ab(&bins);
+        println!("material {material:>2}: {n:>6} positions  a {a:>7.2}  b {b:>6.2}");
+        let m = material as f64 / 58.0;
+        a_points.push((m, a, n));
+        b_points.push((m, b, n));
+    }
+    if a_points.len() < 4 {
+        eprintln!("not enough positions to fit the model");
+        exit(1);
+    }
+
+    let format = |coefficients: [f64; 4]| coefficients.map(|c| format!("{c:.2}")).join(", ");
+    println!("const AS: [f64; 4] = [{}];", format(fit_cubic(&a_points)));
+    println!("const BS: [f64; 4] = [{}];", format(fit_cubic(&b_points)));
+}
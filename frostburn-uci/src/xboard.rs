@@ -0,0 +1,427 @@
+//! CECP (XBoard) frontend, entered when the first command is `xboard` or `protover`.
+//!
+//! The game is kept here as a start position and move list, and searches are run by the same
+//! search threads as UCI. The thread reporting the result prints `move` itself, since the GUI
+//! won't send anything while it waits, and leaves the move behind for us to play on our board.
+
+use std::io::prelude::Write;
+use std::io::{stdin, stdout, ErrorKind};
+use std::process::exit;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use cozy_chess::util::{display_uci_move, parse_uci_move};
+use cozy_chess::{Board, Color, GameStatus, Move, Square};
+use frostburn::{Eval, SearchInfo};
+
+use crate::{parse, MoveFormat, Protocol, SearchConfig, UciHandler};
+
+struct XboardHandler<'a> {
+    uci: &'a mut UciHandler,
+    start: Board,
+    moves: Vec<Move>,
+    engine: Option<Color>,
+    thinking: bool,
+    analyzing: bool,
+    moves_per_session: u32,
+    increment: Duration,
+    clock: Duration,
+    move_time: Option<Duration>,
+    depth: Option<i16>,
+}
+
+pub fn run(uci: &mut UciHandler, first: &str) -> ! {
+    {
+        let mut guard = uci.shared_data.write().unwrap();
+        guard.0.protocol = Protocol::Xboard;
+        guard.0.mv_format = MoveFormat::Standard;
+        guard.0.post = false;
+    }
+
+    let mut xboard = XboardHandler {
+        uci,
+        start: Board::startpos(),
+        moves: vec![],
+        engine: Some(Color::Black),
+        thinking: false,
+        analyzing: false,
+        moves_per_session: 0,
+        increment: Duration::ZERO,
+        clock: Duration::from_secs(300),
+        move_time: None,
+        depth: None,
+    };
+
+    let mut buf = first.to_owned();
+    loop {
+        if let Err(e) = xboard.handle(&buf) {
+            println!("{e}");
+        }
+
+        buf.clear();
+        match stdin().read_line(&mut buf) {
+            Ok(0) => exit(0),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                println!("Error ({e}): <invalid input>");
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        }
+    }
+}
+
+impl XboardHandler<'_> {
+    fn handle(&mut self, line: &str) -> Result<(), String> {
+        let mut tokens = line.split_ascii_whitespace();
+        let Some(cmd) = tokens.next() else {
+            return Ok(());
+        };
+        let error = |kind: &str| format!("Error ({kind}): {}", line.trim());
+
+        // commands which don't affect the game, so they are fine while thinking
+        match cmd {
+            "xboard" | "accepted" | "rejected" | "random" | "hard" | "easy" | "computer"
+            | "name" | "rating" | "ics" | "otim" | "." | "hint" | "bk" => return Ok(()),
+            "protover" => {
+                println!(
+                    "feature myname=\"Frostburn {}\" setboard=1 usermove=1 ping=1 analyze=1 \
+                     colors=0 sigint=0 sigterm=0 reuse=1 memory=1 smp=1 \
+                     variants=\"normal,fischerandom\"",
+                    env!("CARGO_PKG_VERSION_MAJOR")
+                );
+                println!("feature done=1");
+                return Ok(());
+            }
+            "ping" => {
+                println!("pong {}", tokens.next().unwrap_or(""));
+                return Ok(());
+            }
+            "post" | "nopost" => {
+                self.uci.shared_data.write().unwrap().0.post = cmd == "post";
+                return Ok(());
+            }
+            "time" => {
+                let cs = parse::number(cmd, tokens.next()).map_err(|e| error(&e))?;
+                self.clock = Duration::from_millis(cs.saturating_mul(10));
+                return Ok(());
+            }
+            "?" => {
+                self.uci.shared_data.read().unwrap().1.abort();
+                return Ok(());
+            }
+            "quit" => exit(0),
+            _ => {}
+        }
+
+        // everything else first waits for, or interrupts, the current search
+        let interrupts = matches!(
+            cmd,
+            "new" | "force" | "undo" | "remove" | "setboard" | "result" | "exit" | "variant"
+        );
+        self.finish_search(interrupts || self.analyzing);
+
+        match cmd {
+            "new" => {
+                self.uci.new_game(&mut "".split_ascii_whitespace())?;
+                self.start = Board::startpos();
+                self.moves.clear();
+                self.engine = Some(Color::Black);
+                self.depth = None;
+                self.move_time = None;
+                self.uci.shared_data.write().unwrap().0.mv_format = MoveFormat::Standard;
+            }
+            "variant" => match tokens.next() {
+                Some("normal") => {
+                    self.uci.shared_data.write().unwrap().0.mv_format = MoveFormat::Standard;
+                }
+                Some("fischerandom") => {
+                    self.uci.shared_data.write().unwrap().0.mv_format = MoveFormat::Chess960;
+                }
+                _ => return Err(error("unsupported variant")),
+            },
+            "force" | "result" => self.engine = None,
+            "go" => self.engine = Some(self.position().side_to_move()),
+            "analyze" => {
+                self.engine = None;
+                self.analyzing = true;
+            }
+            "exit" => self.analyzing = false,
+            "setboard" => {
+                let fen = tokens.collect::<Vec<_>>().join(" ");
+                match fen.parse() {
+                    Ok(board) => {
+                        self.start = board;
+                        self.moves.clear();
+                    }
+                    Err(_) => return Err(format!("tellusererror Illegal position: {fen}")),
+                }
+            }
+            "undo" => {
+                self.moves.pop();
+            }
+            "remove" => {
+                self.moves.pop();
+                self.moves.pop();
+            }
+            "level" => {
+                let mut level = || -> Option<(u32, Duration, Duration)> {
+                    let mps = tokens.next()?.parse().ok()?;
+                    let base = tokens.next()?;
+                    let base = match base.split_once(':') {
+                        Some((m, s)) => 60 * m.parse::<u64>().ok()? + s.parse::<u64>().ok()?,
+                        None => 60 * base.parse::<u64>().ok()?,
+                    };
+                    let inc = tokens.next()?.parse::<f64>().ok()?;
+                    Some((
+                        mps,
+                        Duration::from_secs(base),
+                        Duration::from_secs_f64(inc.max(0.0)),
+                    ))
+                };
+                let (mps, base, inc) = level().ok_or_else(|| error("invalid level"))?;
+                self.moves_per_session = mps;
+                self.clock = base;
+                self.increment = inc;
+                self.move_time = None;
+            }
+            "st" => {
+                let secs = tokens
+                    .next()
+                    .and_then(|t| t.parse::<f64>().ok())
+                    .filter(|&s| s >= 0.0)
+                    .ok_or_else(|| error("invalid st"))?;
+                self.move_time = Some(Duration::from_secs_f64(secs));
+            }
+            "sd" => {
+                let depth = tokens
+                    .next()
+                    .and_then(|t| t.parse::<i16>().ok())
+                    .filter(|&d| d > 0)
+                    .ok_or_else(|| error("invalid sd"))?;
+                self.depth = Some(depth);
+            }
+            "memory" | "cores" => {
+                let name = match cmd {
+                    "memory" => "Hash",
+                    _ => "Threads",
+                };
+                let value = tokens.next().unwrap_or("");
+                self.uci
+                    .set_option(&mut format!("name {name} value {value}").split_ascii_whitespace())
+                    .map_err(|e| error(&e))?;
+            }
+            "usermove" => {
+                let token = tokens.next().unwrap_or("");
+                self.user_move(token)?;
+            }
+            _ => {
+                // moves are sent bare if the GUI didn't accept usermove=1
+                if self.user_move(cmd).is_err() {
+                    return Err(error("unknown command"));
+                }
+            }
+        }
+
+        let engine_to_move = self.engine == Some(self.position().side_to_move());
+        match self.game_result() {
+            Some(result) if engine_to_move => {
+                println!("{result}");
+                self.engine = None;
+            }
+            Some(_) => {}
+            None if engine_to_move || self.analyzing => self.search(),
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    fn user_move(&mut self, token: &str) -> Result<(), String> {
+        let board = self.position();
+        let mv_format = self.uci.shared_data.read().unwrap().0.mv_format;
+        let mv =
+            parse_move(&board, token, mv_format).ok_or_else(|| format!("Illegal move: {token}"))?;
+        self.moves.push(mv);
+        Ok(())
+    }
+
+    fn position(&self) -> Board {
+        let mut board = self.start.clone();
+        for &mv in &self.moves {
+            board.play_unchecked(mv);
+        }
+        board
+    }
+
+    fn game_result(&self) -> Option<&'static str> {
+        let mut board = self.start.clone();
+        let mut history = vec![];
+        for &mv in &self.moves {
+            history.push(board.hash());
+            board.play_unchecked(mv);
+        }
+        game_result(&board, &history)
+    }
+
+    fn search(&mut self) {
+        let mut guard = self.uci.shared_data.write().unwrap();
+        let config = &mut guard.0;
+
+        config.position = self.start.clone();
+        config.history.clear();
+        for &mv in &self.moves {
+            config.history.push(config.position.hash());
+            config.position.play_unchecked(mv);
+        }
+
+        config.start = Instant::now();
+        config.limits.unbounded();
+        config.limits.infinite = self.analyzing;
+        config.limits.search_moves.clear();
+        config.limits.mate_search = false;
+        config.limits.depth = self.depth;
+        if !self.analyzing {
+            match self.move_time {
+                Some(move_time) => config.limits.move_time = Some(move_time),
+                None => {
+                    config.limits.clock = Some(self.clock);
+                    config.limits.increment = self.increment;
+                    if self.moves_per_session > 0 {
+                        let played = config.position.fullmove_number() as u32 - 1;
+                        config.limits.moves_to_go =
+                            Some(self.moves_per_session - played % self.moves_per_session);
+                    }
+                }
+            }
+        }
+        *config.xboard_move.get_mut().unwrap() = None;
+
+        drop(guard);
+        self.uci.start_search(false);
+        self.thinking = true;
+    }
+
+    /// Waits for the current search to finish and plays the engine's move. If `interrupt` is set,
+    /// the search is stopped instead and its result thrown away unless it was already sent.
+    fn finish_search(&mut self, interrupt: bool) {
+        if !self.thinking {
+            return;
+        }
+        self.thinking = false;
+
+        if interrupt {
+            let guard = self.uci.shared_data.read().unwrap();
+            // holding the slot orders this against the move being reported
+            let _slot = guard.0.xboard_move.lock().unwrap();
+            guard.0.discard_move.store(true, Ordering::SeqCst);
+            guard.1.abort();
+        }
+        self.uci.rendezvous();
+
+        // a move is only left behind if the GUI was told about it
+        let mut guard = self.uci.shared_data.write().unwrap();
+        if let Some(mv) = guard.0.xboard_move.get_mut().unwrap().take() {
+            self.moves.push(mv);
+        }
+    }
+}
+
+pub fn report_move(config: &SearchConfig, mv: Move) {
+    let mut slot = config.xboard_move.lock().unwrap();
+    if !config.limits.infinite && !config.discard_move.load(Ordering::SeqCst) {
+        println!(
+            "move {}",
+            format_move(&config.position, mv, config.mv_format)
+        );
+        *slot = Some(mv);
+
+        // claim the result right away, the GUI may not send anything until it sees it
+        let mut board = config.position.clone();
+        board.play_unchecked(mv);
+        let mut history = config.history.clone();
+        history.push(config.position.hash());
+        if let Some(result) = game_result(&board, &history) {
+            println!("{result}");
+        }
+        stdout().flush().unwrap();
+    }
+}
+
+/// The result of the game at `board`, reached through the positions hashed in `history`.
+fn game_result(board: &Board, history: &[u64]) -> Option<&'static str> {
+    match board.status() {
+        GameStatus::Won => Some(match board.side_to_move() {
+            Color::White => "0-1 {Black mates}",
+            Color::Black => "1-0 {White mates}",
+        }),
+        GameStatus::Drawn => Some(match board.generate_moves(|_| true) {
+            true => "1/2-1/2 {Fifty move rule}",
+            false => "1/2-1/2 {Stalemate}",
+        }),
+        GameStatus::Ongoing => history
+            .iter()
+            .filter(|&&h| h == board.hash())
+            .nth(1)
+            .map(|_| "1/2-1/2 {Draw by repetition}"),
+    }
+}
+
+fn parse_move(board: &Board, token: &str, mv_format: MoveFormat) -> Option<Move> {
+    let king = board.king(board.side_to_move());
+    let rights = board.castle_rights(board.side_to_move());
+    let rook_file = match token {
+        "O-O" | "0-0" => rights.short,
+        "O-O-O" | "0-0-0" => rights.long,
+        _ => {
+            let mv = match mv_format {
+                MoveFormat::Standard => parse_uci_move(board, token).ok()?,
+                MoveFormat::Chess960 => token.parse().ok()?,
+            };
+            return board.is_legal(mv).then_some(mv);
+        }
+    };
+    let mv = Move {
+        from: king,
+        to: Square::new(rook_file?, king.rank()),
+        promotion: None,
+    };
+    board.is_legal(mv).then_some(mv)
+}
+
+/// Chess960 castling is written as `O-O`/`O-O-O`, since king-captures-rook isn't understood.
+fn format_move(board: &Board, mv: Move, mv_format: MoveFormat) -> String {
+    match mv_format {
+        MoveFormat::Standard => display_uci_move(board, mv).to_string(),
+        MoveFormat::Chess960 if board.colors(board.side_to_move()).has(mv.to) => {
+            match mv.to.file() > mv.from.file() {
+                true => "O-O".to_owned(),
+                false => "O-O-O".to_owned(),
+            }
+        }
+        MoveFormat::Chess960 => mv.to_string(),
+    }
+}
+
+pub fn print_thinking(root: &Board, mv_format: MoveFormat, info: &SearchInfo) {
+    let score = match (info.score.mating_in(), (-info.score).mating_in()) {
+        (Some(n), _) => 100000 + n as i32,
+        (_, Some(n)) => -100000 - n as i32,
+        _ => info.score - Eval::cp(0),
+    };
+    print!(
+        "{} {score} {} {}",
+        info.depth,
+        info.time.as_millis() / 10,
+        info.nodes
+    );
+
+    let mut board = root.clone();
+    for &mv in info.pv {
+        print!(" {}", format_move(&board, mv, mv_format));
+        board.play_unchecked(mv);
+    }
+    println!();
+}
@@ -2,18 +2,32 @@ use core::fmt::Display;
 use core::ops::{Add, Neg, Sub};
 
 use bytemuck::{Pod, TransparentWrapper, Zeroable};
+use cozy_chess::{Board, Piece};
+
+use crate::MAX_PLY;
 
 #[derive(Copy, Clone, Debug, TransparentWrapper, Pod, Zeroable, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
 pub struct Eval(i16);
 
 const MAX_NONMATE: i16 = 29_000;
+const TB_WIN: i16 = 28_000;
+/// Tablebase scores lie above this, since they're reached within `MAX_PLY` of the root, so they
+/// can be adjusted for distance like mate scores.
+const MIN_TB_WIN: i16 = TB_WIN - MAX_PLY as i16;
+/// Static evaluations stay below the tablebase scores, so they're never adjusted for distance.
+const MAX_EVAL: i16 = 27_000;
 
 impl Eval {
     pub fn cp(v: i16) -> Self {
         Eval(v)
     }
 
+    /// A static evaluation, clamped below any tablebase or mate score.
+    pub fn clamped_cp(v: i32) -> Self {
+        Eval(v.clamp(-MAX_EVAL as i32, MAX_EVAL as i32) as i16)
+    }
+
     pub fn mated(ply: usize) -> Self {
         Eval(ply as i16 - 30_000)
     }
@@ -22,6 +36,15 @@ impl Eval {
         Eval(30_000 - ply as i16)
     }
 
+    /// A tablebase win, which sorts below any mate but above any static evaluation.
+    pub fn tb_win(ply: usize) -> Self {
+        Eval(TB_WIN - ply as i16)
+    }
+
+    pub fn tb_loss(ply: usize) -> Self {
+        Eval(ply as i16 - TB_WIN)
+    }
+
     pub fn is_mate(self) -> bool {
         self.0 < -MAX_NONMATE || self.0 > MAX_NONMATE
     }
@@ -30,24 +53,50 @@ impl Eval {
         self.0 < -MAX_NONMATE
     }
 
+    pub fn mating_in(self) -> Option<i16> {
+        (self.0 > MAX_NONMATE).then_some((30_000 - self.0 + 1) / 2)
+    }
+
+    /// Win, draw, and loss probabilities in permille from the perspective of the side to move.
+    pub fn wdl(self, board: &Board) -> (u16, u16, u16) {
+        if self.0 > MAX_NONMATE {
+            return (1000, 0, 0);
+        } else if self.0 < -MAX_NONMATE {
+            return (0, 0, 1000);
+        }
+
+        let material = board.pieces(Piece::Pawn).len()
+            + 3 * board.pieces(Piece::Knight).len()
+            + 3 * board.pieces(Piece::Bishop).len()
+            + 5 * board.pieces(Piece::Rook).len()
+            + 9 * board.pieces(Piece::Queen).len();
+
+        let win = win_rate(self.0, material);
+        let loss = win_rate(-self.0, material);
+        (win, 1000 - win - loss, loss)
+    }
+
     pub fn clamp_nonmate(self) -> Self {
         Eval(self.0.clamp(-MAX_NONMATE, MAX_NONMATE))
     }
 
+    /// Converts a mate or tablebase score counted from the root into one counted from a node `ply`
+    /// plies from the root.
     pub fn sub_time(self, ply: usize) -> Eval {
-        if self.0 < -MAX_NONMATE {
+        if self.0 < -MIN_TB_WIN {
             Eval(self.0 - ply as i16)
-        } else if self.0 > MAX_NONMATE {
+        } else if self.0 > MIN_TB_WIN {
             Eval(self.0 + ply as i16)
         } else {
             self
         }
     }
 
+    /// Inverse of [`Self::sub_time`].
     pub fn add_time(self, ply: usize) -> Eval {
-        if self.0 < -MAX_NONMATE {
+        if self.0 < -MIN_TB_WIN {
             Eval(self.0 + ply as i16)
-        } else if self.0 > MAX_NONMATE {
+        } else if self.0 > MIN_TB_WIN {
             Eval(self.0 - ply as i16)
         } else {
             self
@@ -55,6 +104,25 @@ impl Eval {
     }
 }
 
+/// Logistic win rate model, with material normalized to 58 at the starting position. `a` is the
+/// score at which a win is as likely as not and `b` how quickly that changes, both cubic in the
+/// material.
+///
+/// The coefficients are a maximum likelihood fit to the results of self-play games. To refit,
+/// generate games with `datagen wdl.bin --games 2000` and run `frostburn-uci wdlfit wdl.bin`,
+/// which scores every 4th position with a 5k node search, fits `a` and `b` for each material and
+/// fits the polynomials to those.
+fn win_rate(eval: i16, material: u32) -> u16 {
+    const AS: [f64; 4] = [-170.02, 558.57, -676.62, 466.05];
+    const BS: [f64; 4] = [67.61, -219.10, 301.50, 23.05];
+
+    let m = material.clamp(17, 78) as f64 / 58.0;
+    let a = ((AS[0] * m + AS[1]) * m + AS[2]) * m + AS[3];
+    let b = ((BS[0] * m + BS[1]) * m + BS[2]) * m + BS[3];
+
+    (1000.0 / (1.0 + ((a - eval as f64) / b).exp())).round() as u16
+}
+
 impl Display for Eval {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         if f.alternate() {
@@ -124,3 +192,49 @@ impl Sub<i32> for Eval {
         Eval((self.0 as i32 - rhs).clamp(-30_000, 30_000) as i16)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wdl_is_a_distribution_symmetric_between_the_sides() {
+        for (fen, flipped) in [
+            (
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1",
+            ),
+            (
+                "r1bq1rk1/pp2bppp/2n1pn2/3p4/3P4/2NBPN2/PP3PPP/R2QK2R w KQ - 0 9",
+                "r2qk2r/pp3ppp/2nbpn2/3p4/3P4/2N1PN2/PP2BPPP/R1BQ1RK1 b kq - 0 9",
+            ),
+            (
+                "8/5k2/8/3R4/8/8/2K5/8 w - - 0 1",
+                "8/2k5/8/8/3r4/8/5K2/8 b - - 0 1",
+            ),
+            (
+                "8/8/4k3/8/8/4K3/4P3/8 b - - 0 1",
+                "8/4p3/4k3/8/8/4K3/8/8 w - - 0 1",
+            ),
+            (
+                "4k3/8/8/8/8/8/8/4K3 w - - 0 1",
+                "4k3/8/8/8/8/8/8/4K3 b - - 0 1",
+            ),
+            (
+                "qqqqkqqq/qqqqqqqq/8/8/8/8/QQQQQQQQ/QQQQKQQQ w - - 0 1",
+                "qqqqkqqq/qqqqqqqq/8/8/8/8/QQQQQQQQ/QQQQKQQQ b - - 0 1",
+            ),
+        ] {
+            let board: Board = fen.parse().unwrap();
+            let flipped: Board = flipped.parse().unwrap();
+            for v in (-MAX_NONMATE..=MAX_NONMATE).step_by(7) {
+                let (w, d, l) = Eval(v).wdl(&board);
+                assert_eq!(w + d + l, 1000, "{v} in {fen}");
+                assert_eq!(Eval(-v).wdl(&board), (l, d, w), "{v} in {fen}");
+                assert_eq!(Eval(v).wdl(&flipped), (w, d, l), "{v} in {fen}");
+            }
+            assert_eq!(Eval::mating(5).wdl(&board), (1000, 0, 0));
+            assert_eq!(Eval::mated(5).wdl(&board), (0, 0, 1000));
+        }
+    }
+}
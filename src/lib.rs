@@ -22,10 +22,13 @@ mod nnue;
 mod params;
 mod qsearch;
 mod search;
+mod tablebase;
 mod tt;
 
 pub use crate::eval::Eval;
-pub use crate::nnue::{Nnue, NnueBackend};
+pub use crate::nnue::{InvalidNetworkSize, Nnue, NnueBackend, NnueNetwork};
+pub use crate::tablebase::{InvalidTable, Table, Tablebase, Wdl, TB_MAX_PIECES};
+pub use crate::tt::{InvalidHashFile, MAX_MB as MAX_TT_MB};
 
 #[cfg(feature = "tunable")]
 pub use crate::params::{Tunable, TUNABLES};
@@ -37,6 +40,7 @@ pub struct LocalData {
     pv_table: [ArrayVec<Move, MAX_PLY>; MAX_PLY + 1],
     on_first_depth: bool,
     local_nodes: u64,
+    searched_nodes: u64,
     local_seldepth: i16,
     nnue: Nnue,
     history: ButterflyHistory,
@@ -44,16 +48,28 @@ pub struct LocalData {
     followup_hist: ContinuationHistory,
     prev_moves: [Option<(Move, Piece)>; MAX_PLY],
     prev_evals: [Eval; MAX_PLY],
+    root_excluded: Vec<Move>,
+    pv_index: usize,
+    pondering: bool,
+    time_origin: Duration,
+    tb_probe: bool,
 }
 
 pub struct SharedData {
     abort: AtomicBool,
+    pondering: AtomicBool,
     nodes: AtomicU64,
     selective_depth: AtomicI16,
+    tb_hits: AtomicU64,
     tt: TranspositionTable,
     log_table: [f32; 32],
     pub seed: u64,
     pub nnue_backend: NnueBackend,
+    pub nnue_network: NnueNetwork,
+    pub tablebase: Tablebase,
+    /// Called repeatedly while a search has nothing left to do but may not stop yet, e.g. during
+    /// `go infinite` once the maximum depth is reached. Frontends which can sleep should do so.
+    pub idle: fn(),
 }
 
 pub struct Search<'a> {
@@ -61,29 +77,41 @@ pub struct Search<'a> {
     pub history: Vec<u64>,
     pub clock: &'a dyn Fn() -> Duration,
     pub info: &'a mut dyn FnMut(SearchInfo),
+    pub curr_move: &'a mut dyn FnMut(Move, usize),
     pub data: &'a mut LocalData,
     pub shared: &'a SharedData,
     pub limits: Limits,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Limits {
     pub move_time: Option<Duration>,
     pub clock: Option<Duration>,
     pub increment: Duration,
+    pub moves_to_go: Option<u32>,
+    pub move_overhead: Duration,
     pub depth: Option<i16>,
     pub nodes: Option<u64>,
     pub min_nodes: Option<u64>,
+    pub mate: Option<i16>,
     pub quantize_eval: i16,
+    pub eval_noise: i16,
+    pub root_noise: i16,
+    pub multi_pv: usize,
+    pub infinite: bool,
+    pub search_moves: Vec<Move>,
+    pub mate_search: bool,
 }
 
 impl Limits {
     pub fn unbounded(&mut self) {
         self.move_time = None;
         self.clock = None;
+        self.moves_to_go = None;
         self.depth = None;
         self.nodes = None;
         self.min_nodes = None;
+        self.mate = None;
         self.increment = Duration::ZERO;
     }
 }
@@ -94,10 +122,19 @@ impl Default for Limits {
             move_time: None,
             clock: None,
             increment: Duration::ZERO,
+            moves_to_go: None,
+            move_overhead: Duration::ZERO,
             depth: None,
             nodes: None,
             min_nodes: None,
+            mate: None,
             quantize_eval: 1,
+            eval_noise: 0,
+            root_noise: 0,
+            multi_pv: 1,
+            infinite: false,
+            search_moves: Vec::new(),
+            mate_search: false,
         }
     }
 }
@@ -106,9 +143,13 @@ pub struct SearchInfo<'a> {
     pub depth: i16,
     pub selective_depth: i16,
     pub score: Eval,
+    pub wdl: (u16, u16, u16),
     pub nodes: u64,
+    pub hashfull: u16,
+    pub tb_hits: u64,
     pub time: Duration,
     pub pv: &'a [Move],
+    pub multi_pv: usize,
     pub finished: bool,
 }
 
@@ -126,9 +167,12 @@ impl Search<'_> {
                     .shared
                     .nodes
                     .fetch_add(self.data.local_nodes, Ordering::Relaxed);
+            self.data.searched_nodes += self.data.local_nodes;
             self.data.local_nodes = 0;
 
-            if self.limits.nodes.is_some_and(|n| nodes >= n) || self.check_time() {
+            if self.may_stop()
+                && (self.limits.nodes.is_some_and(|n| nodes >= n) || self.check_time())
+            {
                 self.shared.abort.store(true, Ordering::Relaxed);
             }
         }
@@ -140,8 +184,21 @@ impl Search<'_> {
         Some(())
     }
 
+    fn pondering(&mut self) -> bool {
+        if self.data.pondering && !self.shared.pondering.load(Ordering::Relaxed) {
+            // time limits apply from the moment of the ponderhit
+            self.data.pondering = false;
+            self.data.time_origin = (self.clock)();
+        }
+        self.data.pondering
+    }
+
+    fn may_stop(&mut self) -> bool {
+        !self.limits.infinite && !self.pondering()
+    }
+
     fn check_time(&self) -> bool {
-        let time = (self.clock)();
+        let time = (self.clock)() - self.data.time_origin;
         if self.limits.move_time.is_some_and(|target| time > target) {
             return true;
         }
@@ -149,14 +206,37 @@ impl Search<'_> {
     }
 
     fn eval(&mut self, board: &Board) -> Eval {
-        let mut eval = self.data.nnue.infer(board, self.shared.nnue_backend);
+        let mut eval =
+            self.data
+                .nnue
+                .infer(board, self.shared.nnue_backend, &self.shared.nnue_network);
         eval = eval * (200 - board.halfmove_clock() as i32) / 200;
         if self.limits.quantize_eval != 1 {
             let q = self.limits.quantize_eval as i32;
             let offset = q / 2 * eval.signum();
             eval = (eval + offset) / q * q;
         }
-        Eval::cp(eval.clamp(-29_000, 29_000) as i16)
+        if self.limits.eval_noise != 0 {
+            let n = self.limits.eval_noise as u64;
+            eval += (mix(board.hash() ^ self.shared.seed) % (2 * n + 1)) as i32 - n as i32;
+        }
+        Eval::clamped_cp(eval)
+    }
+
+    /// Probes the tablebase below the root. Probing is disabled when the root itself is in the
+    /// tablebase, since every move keeping the result would score the same and the search would
+    /// have no way to make progress.
+    fn probe_tablebase(&mut self, board: &Board, ply: usize) -> Option<Eval> {
+        if !self.data.tb_probe || board.occupied().len() as usize > TB_MAX_PIECES {
+            return None;
+        }
+        let wdl = self.shared.tablebase.probe(board)?;
+        self.shared.tb_hits.fetch_add(1, Ordering::Relaxed);
+        Some(match wdl {
+            Wdl::Win => Eval::tb_win(ply),
+            Wdl::Draw => Eval::cp(0),
+            Wdl::Loss => Eval::tb_loss(ply),
+        })
     }
 }
 
@@ -166,6 +246,7 @@ impl LocalData {
             pv_table: [(); MAX_PLY + 1].map(|_| ArrayVec::new()),
             on_first_depth: false,
             local_nodes: 0,
+            searched_nodes: 0,
             local_seldepth: 0,
             nnue: Nnue::new(),
             history: ButterflyHistory::new(),
@@ -173,8 +254,18 @@ impl LocalData {
             followup_hist: ContinuationHistory::new(),
             prev_moves: [None; MAX_PLY],
             prev_evals: [Eval::cp(0); MAX_PLY],
+            root_excluded: Vec::new(),
+            pv_index: 0,
+            pondering: false,
+            time_origin: Duration::ZERO,
+            tb_probe: false,
         }
     }
+
+    /// Nodes searched by this thread during the last search.
+    pub fn nodes(&self) -> u64 {
+        self.searched_nodes + self.local_nodes
+    }
 }
 
 impl SharedData {
@@ -185,25 +276,84 @@ impl SharedData {
         }
         SharedData {
             abort: AtomicBool::new(false),
+            pondering: AtomicBool::new(false),
             nodes: AtomicU64::new(0),
             selective_depth: AtomicI16::new(0),
+            tb_hits: AtomicU64::new(0),
             tt: TranspositionTable::new(tt_mb),
             seed: 0x6CA648710DB5F3AE,
             log_table,
             nnue_backend: NnueBackend::default(),
+            nnue_network: NnueNetwork::default(),
+            tablebase: Tablebase::default(),
+            idle: core::hint::spin_loop,
         }
     }
 
     pub fn prepare_for_search(&mut self) {
         *self.abort.get_mut() = false;
+        *self.pondering.get_mut() = false;
         *self.nodes.get_mut() = 0;
         *self.selective_depth.get_mut() = 0;
+        *self.tb_hits.get_mut() = 0;
+        self.tt.new_search();
+    }
+
+    pub fn prepare_for_ponder(&mut self) {
+        self.prepare_for_search();
+        *self.pondering.get_mut() = true;
     }
 
     pub fn abort(&self) {
         self.abort.store(true, Ordering::SeqCst);
     }
 
+    pub fn ponderhit(&self) {
+        self.pondering.store(false, Ordering::SeqCst);
+    }
+
+    pub fn probe_move(&self, board: &Board) -> Option<Move> {
+        let (tt, _) = self.tt.load(board.hash(), 0);
+        tt.map(|tt| tt.mv.into()).filter(|&mv| board.is_legal(mv))
+    }
+
+    /// Replaces the transposition table with one of `tt_mb` megabytes, leaving all other state
+    /// intact. The old table is freed first to avoid holding both at once. The new table is
+    /// expected to be cleared through [`Self::get_clear_tt_blocks`] before it is searched, so that
+    /// its pages are first touched by the search threads rather than by the caller.
+    pub fn resize_tt(&mut self, tt_mb: usize) {
+        self.tt = TranspositionTable::new(0);
+        self.tt = TranspositionTable::new(tt_mb);
+    }
+
+    /// Writes the transposition table along with the size and network needed to load it. No
+    /// search may be running.
+    pub fn save_tt<E>(&self, write: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
+        self.tt.save_to(self.nnue_network.fingerprint(), write)
+    }
+
+    /// Replaces the transposition table with one written by [`Self::save_tt`], resizing it to
+    /// match. Tables saved with a different network are rejected, since their scores would be
+    /// inconsistent with this one's evaluation. If the file is rejected the table is unchanged,
+    /// but if reading fails partway through it is cleared.
+    pub fn load_tt<E: From<InvalidHashFile>>(
+        &mut self,
+        read: impl FnMut(&mut [u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.tt.load_from(self.nnue_network.fingerprint(), read)
+    }
+
+    /// Whether transparent huge pages were requested for the transposition table. Whether the
+    /// kernel actually backs it with them isn't known.
+    pub fn tt_huge_pages_requested(&self) -> bool {
+        self.tt.huge_pages_requested()
+    }
+
+    /// Size of the transposition table in megabytes.
+    pub fn tt_mb(&self) -> usize {
+        self.tt.raw().len() * 8 / (1024 * 1024)
+    }
+
     pub fn get_clear_tt_blocks(&self, count: usize) -> Vec<ClearTtBlock> {
         let size = self.tt.raw().len();
         let block_size = size / count;
@@ -233,6 +383,14 @@ impl SharedData {
     }
 }
 
+/// splitmix64 finalizer, used as a cheap deterministic source of randomness
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^ (x >> 31)
+}
+
 pub struct ClearTtBlock {
     range: Range<usize>,
 }
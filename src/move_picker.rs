@@ -3,20 +3,46 @@ use alloc::vec::Vec;
 use arrayvec::ArrayVec;
 use cozy_chess::{
     get_bishop_moves, get_king_moves, get_knight_moves, get_pawn_attacks, get_rook_moves, BitBoard,
-    Board, Color, Move, Piece, Rank, Square,
+    Board, Color, Move, Piece, PieceMoves, Rank, Square,
 };
 
-use crate::history::PieceHistory;
 use crate::LocalData;
 
+/// Good captures are scored above every quiet and bad captures below, since history is bounded
+/// well within the gap. Underpromotions are pushed below everything else.
+const GOOD_CAPTURE: i32 = 100_000;
+const BAD_CAPTURE: i32 = -100_000;
+const TT_MOVE: i32 = 1_000_000;
+
+/// Yields moves in stages, doing only the work each stage needs: the TT move is tried before any
+/// moves are generated, captures are generated and scored by SEE once it fails to cut off, and
+/// quiets are only generated and scored from history once every good capture has been tried.
+/// Bad captures and underpromotions are set aside until the end, so no stage scans moves it
+/// won't pick.
 pub struct MovePicker<'a> {
-    _skip_quiets: bool,
-    has_moves: bool,
-    _board: &'a Board,
+    board: &'a Board,
+    tt_mv: Option<Move>,
+    excluded: Vec<Move>,
+    skip_quiets: bool,
+    counter_prior: Option<(Move, Piece)>,
+    followup_prior: Option<(Move, Piece)>,
+    stage: Stage,
+    piece_moves: Option<ArrayVec<PieceMoves, 32>>,
+    /// Moves already tried, in order, followed by the candidates of the current stage.
     moves: Vec<ScoredMove>,
+    deferred: Vec<ScoredMove>,
     next_idx: usize,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Stage {
+    TtMove,
+    GenerateCaptures,
+    GoodCaptures,
+    Quiets,
+    BadCaptures,
+}
+
 pub struct ScoredMove {
     pub mv: Move,
     pub score: i32,
@@ -27,87 +53,179 @@ pub struct ScoredMove {
 impl<'a> MovePicker<'a> {
     pub fn new(
         board: &'a Board,
-        data: &LocalData,
         tt_mv: Option<Move>,
-        excluded: Option<Move>,
+        excluded: &[Move],
         skip_quiets: bool,
-        counter_hist: Option<&PieceHistory>,
-        followup_hist: Option<&PieceHistory>,
+        counter_prior: Option<(Move, Piece)>,
+        followup_prior: Option<(Move, Piece)>,
     ) -> Self {
-        let mut moves = Vec::with_capacity(64);
-
-        let mut piece_moves = ArrayVec::<_, 32>::new();
-
-        board.generate_moves(|mvs| {
-            piece_moves.push(mvs);
-            false
+        // the TT move may be a hash collision, and must be one the picker would generate
+        let tt_mv = tt_mv.filter(|&mv| {
+            !excluded.contains(&mv)
+                && board.is_legal(mv)
+                && (!skip_quiets || is_noisy(board, mv) && !is_underpromotion(mv))
         });
 
-        let opp = board.colors(!board.side_to_move());
-
-        let promo_rank = Rank::Eighth.relative_to(board.side_to_move()).bitboard();
+        MovePicker {
+            board,
+            tt_mv,
+            excluded: excluded.to_vec(),
+            skip_quiets,
+            counter_prior,
+            followup_prior,
+            stage: Stage::TtMove,
+            piece_moves: None,
+            moves: Vec::new(),
+            deferred: Vec::new(),
+            next_idx: 0,
+        }
+    }
 
-        for &(mut mvs) in &piece_moves {
-            if skip_quiets {
-                if mvs.piece == Piece::Pawn {
-                    mvs.to &= opp | promo_rank;
-                } else {
-                    mvs.to &= opp;
+    #[inline(always)]
+    pub fn next(&mut self, data: &LocalData) -> Option<(usize, &ScoredMove)> {
+        let i = loop {
+            match self.stage {
+                Stage::TtMove => {
+                    self.stage = Stage::GenerateCaptures;
+                    if let Some(mv) = self.tt_mv {
+                        self.moves.push(ScoredMove {
+                            mv,
+                            score: TT_MOVE,
+                            see: 0,
+                            history: 0,
+                        });
+                        self.next_idx = 1;
+                        break 0;
+                    }
+                }
+                Stage::GenerateCaptures => {
+                    self.stage = Stage::GoodCaptures;
+                    self.push_captures();
                 }
+                Stage::GoodCaptures => {
+                    if let Some(i) = self.select() {
+                        break i;
+                    }
+                    self.stage = Stage::Quiets;
+                    self.push_quiets(data);
+                }
+                Stage::Quiets => {
+                    if let Some(i) = self.select() {
+                        break i;
+                    }
+                    self.stage = Stage::BadCaptures;
+                    self.moves.append(&mut self.deferred);
+                }
+                Stage::BadCaptures => break self.select()?,
             }
+        };
+        Some((i, &self.moves[i]))
+    }
+
+    /// Moves tried before the current one.
+    pub fn failed(&self) -> impl Iterator<Item = &ScoredMove> + '_ {
+        self.moves[..self.next_idx - 1].iter()
+    }
+
+    pub fn has_moves(&mut self) -> bool {
+        self.tt_mv.is_some() || !self.piece_moves().is_empty()
+    }
+
+    fn piece_moves(&mut self) -> &ArrayVec<PieceMoves, 32> {
+        let board = self.board;
+        self.piece_moves.get_or_insert_with(|| {
+            let mut piece_moves = ArrayVec::new();
+            board.generate_moves(|mvs| {
+                piece_moves.push(mvs);
+                false
+            });
+            piece_moves
+        })
+    }
+
+    /// Queues the moves of each piece to the squares `to` returns for it, except the TT move,
+    /// excluded moves, and underpromotions when skipping quiets. Moves scoring below `min_score`
+    /// are deferred to the last stage.
+    fn push(
+        &mut self,
+        to: impl Fn(Piece) -> BitBoard,
+        min_score: i32,
+        mut score: impl FnMut(Move) -> ScoredMove,
+    ) {
+        self.piece_moves();
+        for &(mut mvs) in self.piece_moves.as_ref().unwrap() {
+            mvs.to &= to(mvs.piece);
             for mv in mvs {
-                if excluded.is_some_and(|excluded| excluded == mv) {
+                if Some(mv) == self.tt_mv
+                    || self.excluded.contains(&mv)
+                    || self.skip_quiets && is_underpromotion(mv)
+                {
                     continue;
                 }
-                if skip_quiets && !matches!(mv.promotion, None | Some(Piece::Queen)) {
-                    continue;
+                let scored = score(mv);
+                match scored.score >= min_score {
+                    true => self.moves.push(scored),
+                    false => self.deferred.push(scored),
                 }
-                let mut see_score = 0;
-                let mut history = 0;
-                let mut score = match tt_mv {
-                    Some(tt_mv) if mv == tt_mv => 1_000_000,
-                    _ if opp.has(mv.to) => {
-                        see_score = see(board, mv);
-                        let base = see_score * 10 + board.piece_on(mv.to).unwrap() as i32;
-                        if see_score < 0 {
-                            -100_000 + base
-                        } else {
-                            100_000 + base
-                        }
-                    }
-                    _ => {
-                        history = data.history.get(board, mv) as i32 / 2
-                            + counter_hist.map_or(0, |table| table.get(board, mv) as i32)
-                            + followup_hist.map_or(0, |table| table.get(board, mv) as i32);
-                        history
-                    }
-                };
-                match mv.promotion {
-                    Some(Piece::Knight) => score -= 400_000,
-                    Some(Piece::Rook) => score -= 500_000,
-                    Some(Piece::Bishop) => score -= 600_000,
-                    _ => {}
-                }
-                moves.push(ScoredMove {
-                    mv,
-                    score,
-                    see: see_score,
-                    history,
-                });
             }
         }
+    }
 
-        MovePicker {
-            _skip_quiets: skip_quiets,
-            _board: board,
-            moves,
-            next_idx: 0,
-            has_moves: !piece_moves.is_empty(),
-        }
+    fn push_captures(&mut self) {
+        let board = self.board;
+        let opp = board.colors(!board.side_to_move());
+        self.push(
+            |_| opp,
+            GOOD_CAPTURE,
+            |mv| {
+                let see = see(board, mv);
+                let base = see * 10 + board.piece_on(mv.to).unwrap() as i32;
+                ScoredMove {
+                    mv,
+                    score: promotion_score(
+                        mv,
+                        match see < 0 {
+                            true => BAD_CAPTURE + base,
+                            false => GOOD_CAPTURE + base,
+                        },
+                    ),
+                    see,
+                    history: 0,
+                }
+            },
+        );
     }
 
-    #[inline(always)]
-    pub fn next(&mut self, _data: &LocalData) -> Option<(usize, &ScoredMove)> {
+    fn push_quiets(&mut self, data: &LocalData) {
+        let board = self.board;
+        // castling is encoded as capturing the own rook, so it's included here
+        let quiet = !board.colors(!board.side_to_move());
+        let promo_rank = Rank::Eighth.relative_to(board.side_to_move()).bitboard();
+        // only queen promotions are tried when skipping quiets
+        let skip_quiets = self.skip_quiets;
+        let to = move |piece| match skip_quiets {
+            true if piece == Piece::Pawn => quiet & promo_rank,
+            true => BitBoard::EMPTY,
+            false => quiet,
+        };
+        let counter_hist = data.counter_hist.get(self.counter_prior);
+        let followup_hist = data.followup_hist.get(self.followup_prior);
+        self.push(to, BAD_CAPTURE, |mv| {
+            let history = data.history.get(board, mv) as i32 / 2
+                + counter_hist.map_or(0, |table| table.get(board, mv) as i32)
+                + followup_hist.map_or(0, |table| table.get(board, mv) as i32);
+            ScoredMove {
+                mv,
+                score: promotion_score(mv, history),
+                see: 0,
+                history,
+            }
+        });
+    }
+
+    /// Moves the best remaining move to the front of the remaining moves. Ties go to the move
+    /// nearest the front.
+    fn select(&mut self) -> Option<usize> {
         let i = self.next_idx;
         let (best, _) = self
             .moves
@@ -117,15 +235,26 @@ impl<'a> MovePicker<'a> {
             .min_by_key(|&(_, mv)| core::cmp::Reverse(mv))?;
         self.moves.swap(i, best);
         self.next_idx += 1;
-        Some((i, &self.moves[i]))
+        Some(i)
     }
+}
 
-    pub fn failed(&self) -> impl Iterator<Item = &ScoredMove> + '_ {
-        self.moves[..self.next_idx - 1].iter()
-    }
+fn is_noisy(board: &Board, mv: Move) -> bool {
+    let promo_rank = Rank::Eighth.relative_to(board.side_to_move()).bitboard();
+    board.colors(!board.side_to_move()).has(mv.to)
+        || board.piece_on(mv.from) == Some(Piece::Pawn) && promo_rank.has(mv.to)
+}
+
+fn is_underpromotion(mv: Move) -> bool {
+    !matches!(mv.promotion, None | Some(Piece::Queen))
+}
 
-    pub fn has_moves(&self) -> bool {
-        self.has_moves
+fn promotion_score(mv: Move, score: i32) -> i32 {
+    match mv.promotion {
+        Some(Piece::Knight) => score - 400_000,
+        Some(Piece::Rook) => score - 500_000,
+        Some(Piece::Bishop) => score - 600_000,
+        _ => score,
     }
 }
 
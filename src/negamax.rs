@@ -23,6 +23,12 @@ impl Search<'_> {
 
         self.count_node_and_check_abort(false)?;
 
+        if ply > 0 && excluded.is_none() {
+            if let Some(score) = self.probe_tablebase(pos, ply) {
+                return Some(score);
+            }
+        }
+
         if ply as i16 > self.data.local_seldepth {
             self.data.local_seldepth = ply as i16;
             self.shared
@@ -65,7 +71,10 @@ impl Search<'_> {
 
         let eval = tt.map_or(static_eval, |tt| tt.score);
 
-        if !PV && excluded.is_none() && pos.checkers().is_empty() {
+        // pruning which could hide a defence is disabled when searching for mates
+        let prune = !PV && !self.limits.mate_search;
+
+        if prune && excluded.is_none() && pos.checkers().is_empty() {
             if depth <= rfp_max_depth() && eval >= beta + rfp_margin() * (depth - improving as i16)
             {
                 return Some(eval);
@@ -103,12 +112,14 @@ impl Search<'_> {
         let mut best_score = Eval::mated(0);
         let mut move_picker = MovePicker::new(
             pos,
-            self.data,
             tt_mv,
-            excluded,
+            match ply {
+                0 => &self.data.root_excluded,
+                _ => excluded.as_slice(),
+            },
             false,
-            self.data.counter_hist.get(counter_prior),
-            self.data.followup_hist.get(followup_prior),
+            counter_prior,
+            followup_prior,
         );
 
         if pos.halfmove_clock() >= 100 && move_picker.has_moves() {
@@ -130,11 +141,11 @@ impl Search<'_> {
 
             let quiet = !pos.colors(!pos.side_to_move()).has(scored_mv.mv.to);
 
-            if !PV && quiet && !best_score.losing() && lmp_quiets_to_try <= 0 {
+            if prune && quiet && !best_score.losing() && lmp_quiets_to_try <= 0 {
                 continue;
             }
 
-            if !PV
+            if prune
                 && !quiet
                 && !best_score.losing()
                 && depth < 4
@@ -150,6 +161,10 @@ impl Search<'_> {
             self.data.prev_moves[ply] = Some((scored_mv.mv, piece));
             lmp_quiets_to_try -= quiet as i32;
 
+            if ply == 0 {
+                (self.curr_move)(scored_mv.mv, i + 1);
+            }
+
             let mut score;
             if ply != 0 && self.history.contains(&new_pos.hash()) {
                 score = Eval::cp(0);
@@ -271,12 +286,11 @@ impl Search<'_> {
         };
 
         let bound = Bound::compute(orig_alpha, beta, best_score);
-        if excluded.is_none() {
+        if excluded.is_none() && (ply > 0 || self.data.pv_index == 0) {
             self.shared.tt.store_search(
                 pos.hash(),
                 ply,
                 TtSearchEntry {
-                    lower_hash_bits: 0,
                     mv: match bound {
                         Bound::UPPER => tt_mv.unwrap_or(Move {
                             from: Square::A1,
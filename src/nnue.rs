@@ -1,7 +1,11 @@
+use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::fmt::Display;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use arrayvec::ArrayVec;
+use bytemuck::Zeroable;
 use cozy_chess::{BitBoard, Board, Color, File, Piece, Square};
 
 #[cfg(target_arch = "x86_64")]
@@ -29,7 +33,21 @@ enum Backend {
     Avx512,
 }
 
+/// Network weights used for evaluation. Defaults to the network embedded at build time.
+#[derive(Clone)]
+pub struct NnueNetwork {
+    id: u64,
+    weights: Option<Arc<Network>>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InvalidNetworkSize {
+    pub expected: usize,
+    pub found: usize,
+}
+
 pub struct Nnue {
+    network: u64,
     white_left: Accumulator,
     white_right: Accumulator,
     black_left: Accumulator,
@@ -61,6 +79,11 @@ struct Network {
     l1: Linear<{ 2 * HL_SIZE }, 1>,
 }
 
+// SAFETY: the network consists only of integer arrays, so all-zero (or any) bytes are valid.
+unsafe impl Zeroable for Network {}
+
+static NEXT_NETWORK_ID: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Default)]
 struct Updates {
     adds: ArrayVec<usize, 32>,
@@ -100,17 +123,103 @@ impl Default for NnueBackend {
     }
 }
 
+impl NnueNetwork {
+    pub const SIZE: usize = core::mem::size_of::<Network>();
+
+    pub fn embedded() -> Self {
+        NnueNetwork {
+            id: 0,
+            weights: None,
+        }
+    }
+
+    /// Loads a network in the same format as the `EVALFILE` used at build time.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, InvalidNetworkSize> {
+        if bytes.len() != Self::SIZE {
+            return Err(InvalidNetworkSize {
+                expected: Self::SIZE,
+                found: bytes.len(),
+            });
+        }
+
+        let mut weights: alloc::boxed::Box<Network> = bytemuck::zeroed_box();
+        // SAFETY: the lengths match, and any bytes are a valid network as noted on `Zeroable`.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                (&mut *weights as *mut Network).cast(),
+                Self::SIZE,
+            );
+        }
+
+        Ok(NnueNetwork {
+            id: NEXT_NETWORK_ID.fetch_add(1, Ordering::Relaxed),
+            weights: Some(weights.into()),
+        })
+    }
+
+    /// Hash of the weights, identifying the network across runs.
+    pub fn fingerprint(&self) -> u64 {
+        // SAFETY: the network consists only of integer arrays without padding, which is also
+        // relied upon by transmuting the embedded network from bytes.
+        let bytes = unsafe {
+            core::slice::from_raw_parts((self.weights() as *const Network).cast::<u8>(), Self::SIZE)
+        };
+        bytes.chunks(8).fold(0, |hash, chunk| {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            crate::mix(hash ^ u64::from_le_bytes(word))
+        })
+    }
+
+    pub fn is_embedded(&self) -> bool {
+        self.weights.is_none()
+    }
+
+    fn weights(&self) -> &Network {
+        self.weights.as_deref().unwrap_or(&NETWORK)
+    }
+}
+
+impl Default for NnueNetwork {
+    fn default() -> Self {
+        NnueNetwork::embedded()
+    }
+}
+
+impl Display for InvalidNetworkSize {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "network is {} bytes, expected {} bytes",
+            self.found, self.expected
+        )
+    }
+}
+
 impl Nnue {
     pub fn new() -> Self {
+        Nnue::for_network(&NnueNetwork::embedded())
+    }
+
+    fn for_network(network: &NnueNetwork) -> Self {
+        let net = network.weights();
         Nnue {
-            white_left: Accumulator::new(0),
-            white_right: Accumulator::new(MIRROR_FLIP),
-            black_left: Accumulator::new(BLACK_FLIP),
-            black_right: Accumulator::new(BLACK_FLIP | MIRROR_FLIP),
+            network: network.id,
+            white_left: Accumulator::new(0, net),
+            white_right: Accumulator::new(MIRROR_FLIP, net),
+            black_left: Accumulator::new(BLACK_FLIP, net),
+            black_right: Accumulator::new(BLACK_FLIP | MIRROR_FLIP, net),
         }
     }
 
-    pub fn infer(&mut self, board: &Board, backend: NnueBackend) -> i32 {
+    pub fn infer(&mut self, board: &Board, backend: NnueBackend, network: &NnueNetwork) -> i32 {
+        // accumulators built from other weights can't be updated incrementally
+        if self.network != network.id {
+            *self = Nnue::for_network(network);
+        }
+        let net = network.weights();
+
         let white_acc = match board.king(Color::White).file() < File::E {
             true => &mut self.white_left,
             false => &mut self.white_right,
@@ -120,8 +229,8 @@ impl Nnue {
             false => &mut self.black_right,
         };
 
-        white_acc.update(board, backend.0);
-        black_acc.update(board, backend.0);
+        white_acc.update(board, backend.0, net);
+        black_acc.update(board, backend.0, net);
 
         let (stm_acc, nstm_acc) = match board.side_to_move() {
             Color::White => (white_acc, black_acc),
@@ -130,29 +239,32 @@ impl Nnue {
 
         let result = match backend.0 {
             #[cfg(target_arch = "x86_64")]
-            Backend::Avx2 => unsafe { avx2::infer(&stm_acc.vector, &nstm_acc.vector) },
+            Backend::Avx2 => unsafe { avx2::infer(&stm_acc.vector, &nstm_acc.vector, net) },
             #[cfg(all(target_arch = "x86_64", feature = "nightly-avx512"))]
-            Backend::Avx512 => unsafe { avx512::infer(&stm_acc.vector, &nstm_acc.vector) },
-            Backend::Scalar => scalar::infer(&stm_acc.vector, &nstm_acc.vector),
+            Backend::Avx512 => unsafe { avx512::infer(&stm_acc.vector, &nstm_acc.vector, net) },
+            Backend::Scalar => scalar::infer(&stm_acc.vector, &nstm_acc.vector, net),
         };
 
         #[cfg(feature = "check-inference")]
-        assert_eq!(scalar::infer(&stm_acc.vector, &nstm_acc.vector), result);
+        assert_eq!(
+            scalar::infer(&stm_acc.vector, &nstm_acc.vector, net),
+            result
+        );
 
         result
     }
 }
 
 impl Accumulator {
-    pub fn new(flip: usize) -> Self {
+    fn new(flip: usize, net: &Network) -> Self {
         Accumulator {
             flip,
             enabled: [[BitBoard::EMPTY; 6]; 2],
-            vector: NETWORK.ft.bias,
+            vector: net.ft.bias,
         }
     }
 
-    fn update(&mut self, board: &Board, backend: Backend) {
+    fn update(&mut self, board: &Board, backend: Backend, net: &Network) {
         let mut updates = Updates::default();
         for color in 0..Color::NUM {
             let color = Color::index(color);
@@ -179,16 +291,16 @@ impl Accumulator {
         #[cfg(feature = "check-inference")]
         let reference = {
             let mut reference = self.clone();
-            scalar::update(&mut reference.vector, &updates);
+            scalar::update(&mut reference.vector, &updates, net);
             reference
         };
 
         match backend {
             #[cfg(target_arch = "x86_64")]
-            Backend::Avx2 => unsafe { avx2::update(&mut self.vector, &updates) },
+            Backend::Avx2 => unsafe { avx2::update(&mut self.vector, &updates, net) },
             #[cfg(all(target_arch = "x86_64", feature = "nightly-avx512"))]
-            Backend::Avx512 => unsafe { avx512::update(&mut self.vector, &updates) },
-            Backend::Scalar => scalar::update(&mut self.vector, &updates),
+            Backend::Avx512 => unsafe { avx512::update(&mut self.vector, &updates, net) },
+            Backend::Scalar => scalar::update(&mut self.vector, &updates, net),
         };
 
         #[cfg(feature = "check-inference")]
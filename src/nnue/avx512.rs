@@ -1,6 +1,6 @@
 use core::arch::x86_64::*;
 
-use super::{Updates, HL_SIZE, NETWORK};
+use super::{Network, Updates, HL_SIZE};
 
 const NEURONS_PER_VECTOR: usize = 512 / 16;
 const VECTORS_PER_BLOCK: usize = 16;
@@ -21,15 +21,15 @@ pub(super) fn available() -> bool {
 }
 
 #[target_feature(enable = "avx512f,avx512bw")]
-pub(super) unsafe fn update(acc: &mut [i16; HL_SIZE], updates: &Updates) {
+pub(super) unsafe fn update(acc: &mut [i16; HL_SIZE], updates: &Updates, net: &Network) {
     for block in (0..HL_VECTORS).step_by(VECTORS_PER_BLOCK) {
-        partial_update(acc, &updates.adds, &updates.rms, block);
+        partial_update(acc, &updates.adds, &updates.rms, block, net);
     }
 }
 
 #[target_feature(enable = "avx512f,avx512bw")]
-pub(super) unsafe fn infer(stm: &[i16; HL_SIZE], nstm: &[i16; HL_SIZE]) -> i32 {
-    let (first, last) = NETWORK.l1.w[0].split_at(HL_SIZE);
+pub(super) unsafe fn infer(stm: &[i16; HL_SIZE], nstm: &[i16; HL_SIZE], net: &Network) -> i32 {
+    let (first, last) = net.l1.w[0].split_at(HL_SIZE);
     let first = <&[_; HL_SIZE]>::try_from(first).unwrap();
     let last = <&[_; HL_SIZE]>::try_from(last).unwrap();
 
@@ -40,25 +40,31 @@ pub(super) unsafe fn infer(stm: &[i16; HL_SIZE], nstm: &[i16; HL_SIZE]) -> i32 {
 
     let result = _mm512_reduce_add_epi32(result);
 
-    (NETWORK.l1.bias[0] + result) / 256 / 64
+    (net.l1.bias[0] + result) / 256 / 64
 }
 
 #[target_feature(enable = "avx512f,avx512bw")]
-unsafe fn partial_update(acc: &mut [i16; HL_SIZE], adds: &[usize], subs: &[usize], block: usize) {
+unsafe fn partial_update(
+    acc: &mut [i16; HL_SIZE],
+    adds: &[usize],
+    subs: &[usize],
+    block: usize,
+    net: &Network,
+) {
     let acc: *mut __m512i = acc.as_mut_ptr().cast();
     let mut intermediates = [_mm512_setzero_si512(); VECTORS_PER_BLOCK];
     for i in 0..VECTORS_PER_BLOCK {
         intermediates[i] = _mm512_loadu_si512(acc.add(block + i).cast());
     }
     for &add in adds {
-        let add: *const __m512i = NETWORK.ft.w[add].as_ptr().cast();
+        let add: *const __m512i = net.ft.w[add].as_ptr().cast();
         for i in 0..VECTORS_PER_BLOCK {
             let v = _mm512_loadu_si512(add.add(block + i).cast());
             intermediates[i] = _mm512_add_epi16(intermediates[i], v);
         }
     }
     for &sub in subs {
-        let sub: *const __m512i = NETWORK.ft.w[sub].as_ptr().cast();
+        let sub: *const __m512i = net.ft.w[sub].as_ptr().cast();
         for i in 0..VECTORS_PER_BLOCK {
             let v = _mm512_loadu_si512(sub.add(block + i).cast());
             intermediates[i] = _mm512_sub_epi16(intermediates[i], v);
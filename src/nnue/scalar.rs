@@ -1,21 +1,21 @@
-use super::{Updates, HL_SIZE, NETWORK};
+use super::{Network, Updates, HL_SIZE};
 
-pub(super) fn update(acc: &mut [i16; HL_SIZE], updates: &Updates) {
+pub(super) fn update(acc: &mut [i16; HL_SIZE], updates: &Updates, net: &Network) {
     for &add in &updates.adds {
-        let add = &NETWORK.ft.w[add];
+        let add = &net.ft.w[add];
         for i in 0..acc.len() {
             acc[i] += add[i];
         }
     }
     for &rm in &updates.rms {
-        let rm = &NETWORK.ft.w[rm];
+        let rm = &net.ft.w[rm];
         for i in 0..acc.len() {
             acc[i] -= rm[i];
         }
     }
 }
 
-pub(super) fn infer(stm: &[i16; HL_SIZE], nstm: &[i16; HL_SIZE]) -> i32 {
+pub(super) fn infer(stm: &[i16; HL_SIZE], nstm: &[i16; HL_SIZE], net: &Network) -> i32 {
     let mut activated = [0; HL_SIZE * 2];
     let (left, right) = activated.split_at_mut(HL_SIZE);
     let left = <&mut [_; HL_SIZE]>::try_from(left).unwrap();
@@ -24,10 +24,10 @@ pub(super) fn infer(stm: &[i16; HL_SIZE], nstm: &[i16; HL_SIZE]) -> i32 {
     *left = crelu(stm);
     *right = crelu(nstm);
 
-    let mut result = NETWORK.l1.bias[0];
+    let mut result = net.l1.bias[0];
 
     for i in 0..activated.len() {
-        result += activated[i] as i32 * activated[i] as i32 * NETWORK.l1.w[0][i] as i32;
+        result += activated[i] as i32 * activated[i] as i32 * net.l1.w[0][i] as i32;
     }
 
     result / 256 / 64
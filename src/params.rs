@@ -60,6 +60,7 @@ params! {
     asp_initial: 1..=100 = 18;
     asp_widening: 1..=200 = 101;
     tm_soft_limit: 2..=200 = 26;
+    tm_hard_limit: 10..=100 = 40;
     razor_max_depth: 1..=10 = 3;
     razor_base: 0..=500 = 240;
     razor_margin: 0..=200 = 56;
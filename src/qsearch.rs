@@ -14,6 +14,10 @@ impl Search<'_> {
     ) -> Option<Eval> {
         self.count_node_and_check_abort(false)?;
 
+        if let Some(score) = self.probe_tablebase(pos, ply) {
+            return Some(score);
+        }
+
         let (tt, tt_eval) = self.shared.tt.load(pos.hash(), ply);
         let tt_mv = tt.map(|tt| tt.mv.into());
 
@@ -42,7 +46,7 @@ impl Search<'_> {
             alpha = stand_pat;
         }
 
-        let mut move_picker = MovePicker::new(pos, self.data, tt_mv, None, true, None, None);
+        let mut move_picker = MovePicker::new(pos, tt_mv, &[], true, None, None);
 
         if !move_picker.has_moves() {
             if pos.checkers().is_empty() {
@@ -82,7 +86,6 @@ impl Search<'_> {
                 pos.hash(),
                 ply,
                 TtSearchEntry {
-                    lower_hash_bits: 0,
                     mv: best_mv.into(),
                     score: best_score,
                     depth: 0,
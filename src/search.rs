@@ -1,18 +1,29 @@
+use alloc::vec::Vec;
+use core::cmp::Reverse;
 use core::sync::atomic::Ordering;
+use core::time::Duration;
 
 use arrayvec::ArrayVec;
+use cozy_chess::Move;
 
 use crate::params::*;
-use crate::{Eval, Search, SearchInfo, MAX_DEPTH};
+use crate::{mix, Eval, Search, SearchInfo, Wdl, MAX_DEPTH, MAX_PLY};
+
+struct RootLine {
+    depth: i16,
+    score: Eval,
+    pv: ArrayVec<Move, MAX_PLY>,
+}
 
 impl Search<'_> {
     pub fn search(mut self) {
-        let mut score = Eval::cp(0);
-        let mut pv = ArrayVec::new();
         let mut depth = 0;
 
         self.data.on_first_depth = true;
         self.data.local_seldepth = 0;
+        self.data.searched_nodes = 0;
+        self.data.pondering = self.shared.pondering.load(Ordering::SeqCst);
+        self.data.time_origin = Duration::ZERO;
 
         // simplify history so we can detect 2-fold
         let start =
@@ -27,87 +38,205 @@ impl Search<'_> {
         self.data.counter_hist.decay();
         self.data.followup_hist.decay();
 
-        // calculate hard time limit if playing on clock
-        if let Some(clock) = self.limits.clock {
-            self.limits.move_time = Some(clock / 2);
+        let mut root_moves = Vec::new();
+        let mut filtered_moves = Vec::new();
+        self.root.generate_moves(|mvs| {
+            for mv in mvs {
+                match self.limits.search_moves.is_empty() || self.limits.search_moves.contains(&mv)
+                {
+                    true => root_moves.push(mv),
+                    false => filtered_moves.push(mv),
+                }
+            }
+            false
+        });
+        if root_moves.is_empty() {
+            root_moves.append(&mut filtered_moves);
+        }
+
+        // when the root is in the tablebase, only search moves which keep the result
+        let root_wdl = self.shared.tablebase.probe(self.root);
+        self.data.tb_probe = root_wdl.is_none() && !self.shared.tablebase.is_empty();
+        if let Some(wdl) = root_wdl {
+            self.shared.tb_hits.fetch_add(1, Ordering::Relaxed);
+            let (keep, drop): (Vec<_>, Vec<_>) = root_moves.iter().partition(|&&mv| {
+                let mut child = self.root.clone();
+                child.play_unchecked(mv);
+                self.shared
+                    .tablebase
+                    .probe(&child)
+                    .is_none_or(|v| -v == wdl)
+            });
+            if !keep.is_empty() {
+                root_moves = keep;
+                filtered_moves.extend(drop);
+            }
         }
 
-        let soft_time_limit = self
-            .limits
-            .clock
-            .map(|clock| clock / tm_soft_limit() as u32 + self.limits.increment / 2);
+        // weakened play needs a few extra lines to pick a suboptimal move from
+        let searched_lines = match self.limits.root_noise {
+            0 => self.limits.multi_pv,
+            _ => self.limits.multi_pv.max(4),
+        }
+        .clamp(1, root_moves.len().max(1));
+        let multi_pv = self.limits.multi_pv.clamp(1, searched_lines);
+        let mut lines: Vec<_> = (0..searched_lines)
+            .map(|_| RootLine {
+                depth: 0,
+                score: Eval::cp(0),
+                pv: ArrayVec::new(),
+            })
+            .collect();
+
+        // calculate time limits if playing on clock
+        let mut soft_time_limit = None;
+        if let Some(clock) = self.limits.clock {
+            let available = clock.saturating_sub(self.limits.move_overhead);
+            let horizon = self.limits.moves_to_go.map_or(tm_soft_limit() as u32, |n| {
+                n.clamp(1, tm_soft_limit() as u32)
+            });
+
+            let max_time = available * 3 / 4;
+            let soft = (available / horizon + self.limits.increment / 2).min(max_time);
+            let hard = (soft * tm_hard_limit() as u32 / 10).min(max_time);
+
+            soft_time_limit = Some(soft);
+            self.limits.move_time = Some(self.limits.move_time.map_or(hard, |t| t.min(hard)));
+        }
 
         for new_depth in 1.. {
-            let mut delta = asp_initial() as i32;
-
-            let (mut lower, mut upper) = match new_depth {
-                1 => (Eval::mated(0), Eval::mating(0)),
-                _ => (score - delta, score + delta),
-            };
-
-            let mut result;
-            let mut fail_highs = 0;
-            loop {
-                result = self.negamax::<true>(
-                    self.root,
-                    lower,
-                    upper,
-                    new_depth - fail_highs / 2,
-                    0,
-                    None,
+            let mut completed = true;
+
+            for pv_idx in 0..searched_lines {
+                // exclude root moves which were already reported by a better line
+                self.data.pv_index = pv_idx;
+                self.data.root_excluded.clear();
+                self.data.root_excluded.extend_from_slice(&filtered_moves);
+                self.data.root_excluded.extend(
+                    lines[..pv_idx]
+                        .iter()
+                        .filter_map(|line| line.pv.first().copied()),
                 );
 
-                let Some(result) = result else {
-                    break;
+                let score = lines[pv_idx].score;
+                let mut delta = asp_initial() as i32;
+
+                let (mut lower, mut upper) = match new_depth {
+                    1 => (Eval::mated(0), Eval::mating(0)),
+                    _ => (score - delta, score + delta),
                 };
 
-                match () {
-                    _ if result <= lower => {
-                        upper = upper - (upper - lower) / 2;
-                        lower = result - delta;
-                    }
-                    _ if result >= upper => {
-                        fail_highs += 1;
-                        upper = result + delta;
+                let mut result;
+                let mut fail_highs = 0;
+                loop {
+                    result = self.negamax::<true>(
+                        self.root,
+                        lower,
+                        upper,
+                        new_depth - fail_highs / 2,
+                        0,
+                        None,
+                    );
+
+                    let Some(result) = result else {
+                        break;
+                    };
+
+                    match () {
+                        _ if result <= lower => {
+                            upper = upper - (upper - lower) / 2;
+                            lower = result - delta;
+                        }
+                        _ if result >= upper => {
+                            fail_highs += 1;
+                            upper = result + delta;
+                        }
+                        _ => break,
                     }
-                    _ => break,
+
+                    delta += delta * asp_widening() as i32 / 100;
                 }
 
-                delta += delta * asp_widening() as i32 / 100;
+                let Some(new_score) = result else {
+                    completed = false;
+                    break;
+                };
+
+                lines[pv_idx] = RootLine {
+                    depth: new_depth,
+                    score: new_score,
+                    pv: self.data.pv_table[0].clone(),
+                };
+                lines[..=pv_idx].sort_by_key(|line| Reverse(line.score));
             }
 
             self.data.on_first_depth = false;
-            if let Some(new_score) = result {
-                score = new_score;
-                pv = self.data.pv_table[0].clone();
+            if completed {
                 depth = new_depth;
             }
 
-            let mut finished = result.is_none() || self.count_node_and_check_abort(true).is_none();
+            if depth == MAX_DEPTH {
+                // we can't search any deeper, so wait until we're allowed to stop
+                while !self.may_stop() && !self.shared.abort.load(Ordering::SeqCst) {
+                    (self.shared.idle)();
+                }
+            }
+
+            let mut finished = !completed || self.count_node_and_check_abort(true).is_none();
             let nodes = self.shared.nodes.load(Ordering::SeqCst);
             let seldepth = self.shared.selective_depth.load(Ordering::SeqCst);
+            let hashfull = self.shared.tt.hashfull();
+            let tb_hits = self.shared.tb_hits.load(Ordering::SeqCst);
 
             let time = (self.clock)();
 
-            if depth == MAX_DEPTH
-                || self.limits.depth.is_some_and(|d| d == depth)
-                || self.limits.min_nodes.is_some_and(|n| nodes >= n)
-                || soft_time_limit.is_some_and(|c| time >= c)
+            if self.may_stop()
+                && (depth == MAX_DEPTH
+                    || self.limits.depth.is_some_and(|d| d <= depth)
+                    || self.limits.min_nodes.is_some_and(|n| nodes >= n)
+                    || self
+                        .limits
+                        .mate
+                        .is_some_and(|n| lines[0].score.mating_in().is_some_and(|m| m <= n))
+                    || soft_time_limit.is_some_and(|c| time - self.data.time_origin >= c))
             {
                 self.shared.abort.store(true, Ordering::SeqCst);
                 finished = true;
             }
 
-            let info = SearchInfo {
-                depth,
-                score,
-                nodes,
-                time,
-                pv: &pv,
-                finished,
-                selective_depth: seldepth,
-            };
-            (self.info)(info);
+            if finished && self.limits.root_noise > 0 {
+                let noise = self.limits.root_noise as u64;
+                let seed = self.shared.seed ^ self.root.hash();
+                let chosen = (0..searched_lines)
+                    .max_by_key(|&i| lines[i].score + (mix(seed ^ i as u64) % noise) as i32)
+                    .unwrap();
+                lines[..=chosen].rotate_right(1);
+            }
+
+            for (i, line) in lines[..multi_pv].iter().enumerate() {
+                // the tablebase result is exact, unless the search already found a mate
+                let score = match root_wdl {
+                    Some(_) if line.score.is_mate() => line.score,
+                    Some(Wdl::Win) => Eval::tb_win(0),
+                    Some(Wdl::Draw) => Eval::cp(0),
+                    Some(Wdl::Loss) => Eval::tb_loss(0),
+                    None => line.score,
+                };
+                let info = SearchInfo {
+                    depth: line.depth,
+                    score,
+                    wdl: score.wdl(self.root),
+                    nodes,
+                    hashfull,
+                    tb_hits,
+                    time,
+                    pv: &line.pv,
+                    multi_pv: i + 1,
+                    finished: finished && i == multi_pv - 1,
+                    selective_depth: seldepth,
+                };
+                (self.info)(info);
+            }
 
             if finished {
                 break;
@@ -0,0 +1,647 @@
+//! Win/draw/loss tablebases for endings with up to four pieces.
+//!
+//! Tables are generated by retrograde analysis: positions which are decided immediately (mate,
+//! stalemate, or a capture or promotion into an already solved table) are resolved first, and
+//! results are then propagated backwards through un-moves until nothing changes. Positions which
+//! remain unresolved are draws. The fifty move rule is ignored: results assume the halfmove clock
+//! is zero and that a won position can be converted in however many moves it takes, so a
+//! tablebase win may still be drawn by the rule in play.
+//!
+//! Each table covers one material configuration, with the stronger side generated as white and
+//! the defending side as black. Positions with the colors reversed are probed by mirroring the
+//! board. Indices are formed from the piece squares after mirroring the stronger king into the
+//! a1-d4 quadrant (or onto the a-d files when there are pawns), and results take two bits each.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Display;
+use core::ops::Neg;
+
+use arrayvec::ArrayVec;
+use cozy_chess::{
+    get_bishop_moves, get_king_moves, get_knight_moves, get_pawn_attacks, get_rook_moves, BitBoard,
+    Board, BoardBuilder, Color, Move, Piece, Rank, Square,
+};
+
+pub const TB_MAX_PIECES: usize = 4;
+
+const MAGIC: &[u8; 8] = b"FBWDL\0\0\x01";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Wdl {
+    Loss,
+    Draw,
+    Win,
+}
+
+#[derive(Default)]
+pub struct Tablebase {
+    tables: Vec<Table>,
+}
+
+pub struct Table {
+    strong: ArrayVec<Piece, 2>,
+    weak: ArrayVec<Piece, 2>,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum InvalidTable {
+    Name,
+    Header,
+    Size { expected: usize, found: usize },
+    MissingDependency(String),
+}
+
+// per-position state during generation
+const UNKNOWN: u8 = 0;
+const DRAW: u8 = 1;
+const WIN: u8 = 2;
+const LOSS: u8 = 3;
+const INVALID: u8 = 4;
+
+impl Tablebase {
+    /// Names of all tables in the order they have to be generated, since captures and promotions
+    /// lead into earlier tables.
+    pub fn table_names() -> Vec<String> {
+        const PIECES: [Piece; 5] = [
+            Piece::Queen,
+            Piece::Rook,
+            Piece::Bishop,
+            Piece::Knight,
+            Piece::Pawn,
+        ];
+        let mut sides: Vec<ArrayVec<Piece, 2>> = vec![ArrayVec::new()];
+        for (i, &a) in PIECES.iter().enumerate() {
+            sides.push([a].into_iter().collect());
+            for &b in &PIECES[i..] {
+                sides.push([a, b].into_iter().collect());
+            }
+        }
+
+        let mut tables = vec![];
+        for strong in &sides {
+            for weak in &sides {
+                let count = strong.len() + weak.len();
+                if count == 0 || count + 2 > TB_MAX_PIECES || !canonical(strong, weak) {
+                    continue;
+                }
+                tables.push(Table {
+                    strong: strong.clone(),
+                    weak: weak.clone(),
+                    data: vec![],
+                });
+            }
+        }
+        tables.sort_by_key(|t| (t.strong.len() + t.weak.len(), t.pawns()));
+        tables.iter().map(|t| t.name()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    pub fn add(&mut self, table: Table) {
+        self.tables.retain(|t| t.name() != table.name());
+        self.tables.push(table);
+    }
+
+    /// Generates the named table. Every table it depends on must already have been added.
+    pub fn generate(&self, name: &str) -> Result<Table, InvalidTable> {
+        let (strong, weak) = parse_name(name).ok_or(InvalidTable::Name)?;
+        let mut table = Table {
+            strong,
+            weak,
+            data: vec![],
+        };
+        let size = table.size();
+        let mut state = vec![UNKNOWN; size];
+        let mut moves_left = vec![0u8; size];
+        let mut queue: Vec<u32> = vec![];
+
+        // resolve everything which doesn't depend on other positions in this table
+        for idx in 0..size {
+            let Some(board) = table.board(idx) else {
+                state[idx] = INVALID;
+                continue;
+            };
+
+            let mut count = 0u8;
+            let mut has_moves = false;
+            let mut win = false;
+            let mut missing = None;
+            board.generate_moves(|mvs| {
+                for mv in mvs {
+                    has_moves = true;
+                    let mut child = board.clone();
+                    child.play_unchecked(mv);
+                    let converts =
+                        mv.promotion.is_some() || child.occupied().len() < board.occupied().len();
+                    let value = match converts {
+                        true => match self.probe(&child) {
+                            Some(v) => Some(-v),
+                            None => {
+                                missing = Some(child);
+                                return true;
+                            }
+                        },
+                        false => self
+                            .ep_value(&child)
+                            .filter(|&v| v == Wdl::Win)
+                            .map(Neg::neg),
+                    };
+                    match value {
+                        Some(Wdl::Win) => win = true,
+                        Some(Wdl::Loss) => {}
+                        _ => count += 1,
+                    }
+                }
+                win
+            });
+            if let Some(child) = missing {
+                return Err(InvalidTable::MissingDependency(material_name(&child)));
+            }
+
+            state[idx] = match () {
+                _ if win => WIN,
+                _ if !has_moves && board.checkers().is_empty() => DRAW,
+                _ if count == 0 => LOSS,
+                _ => {
+                    moves_left[idx] = count;
+                    UNKNOWN
+                }
+            };
+            if matches!(state[idx], WIN | LOSS) {
+                queue.push(idx as u32);
+            }
+        }
+
+        // propagate results to the positions leading to them
+        let mut head = 0;
+        while let Some(&idx) = queue.get(head) {
+            head += 1;
+            let value = state[idx as usize];
+            let (squares, strong_to_move) = table.decode(idx as usize);
+            table.unmoves(squares, !strong_to_move, |pred, double_push| {
+                if state[pred] != UNKNOWN {
+                    return;
+                }
+                if let Some((from, to)) = double_push {
+                    // the move actually leads to the position with an en passant capture
+                    let mut child = table.board(pred).unwrap();
+                    child.play_unchecked(Move {
+                        from,
+                        to,
+                        promotion: None,
+                    });
+                    match self.ep_value(&child) {
+                        Some(Wdl::Win) => return,
+                        Some(Wdl::Draw) if value == LOSS => return,
+                        _ => {}
+                    }
+                }
+                if value == LOSS {
+                    state[pred] = WIN;
+                    queue.push(pred as u32);
+                } else {
+                    moves_left[pred] -= 1;
+                    if moves_left[pred] == 0 {
+                        state[pred] = LOSS;
+                        queue.push(pred as u32);
+                    }
+                }
+            });
+        }
+
+        table.data = vec![0; size.div_ceil(4)];
+        for (idx, &s) in state.iter().enumerate() {
+            let bits = match s {
+                WIN => 1,
+                LOSS => 2,
+                _ => 0,
+            };
+            table.data[idx / 4] |= bits << (idx % 4 * 2);
+        }
+        Ok(table)
+    }
+
+    /// Result of the position for the side to move, if it is covered by a loaded table.
+    pub fn probe(&self, board: &Board) -> Option<Wdl> {
+        let pieces = board.occupied().len() as usize;
+        if pieces > TB_MAX_PIECES
+            || [Color::White, Color::Black].into_iter().any(|c| {
+                let rights = board.castle_rights(c);
+                rights.short.is_some() || rights.long.is_some()
+            })
+        {
+            return None;
+        }
+        if pieces == 2 {
+            return Some(Wdl::Draw);
+        }
+
+        let white = side_pieces(board, Color::White);
+        let black = side_pieces(board, Color::Black);
+        let (table, strong) = self.tables.iter().find_map(|t| {
+            match (
+                t.strong == white && t.weak == black,
+                t.strong == black && t.weak == white,
+            ) {
+                (true, _) => Some((t, Color::White)),
+                (_, true) => Some((t, Color::Black)),
+                _ => None,
+            }
+        })?;
+
+        let value = table.get(table.index_of(board, strong));
+        Some(match self.ep_value(board) {
+            Some(ep) => value.max(ep),
+            None => value,
+        })
+    }
+
+    /// The best result the side to move can get by capturing en passant.
+    fn ep_value(&self, board: &Board) -> Option<Wdl> {
+        let file = board.en_passant()?;
+        let stm = board.side_to_move();
+        let target = Square::new(file, Rank::Sixth.relative_to(stm));
+        let mut best = None;
+        for from in get_pawn_attacks(target, !stm) & board.colored_pieces(stm, Piece::Pawn) {
+            let mv = Move {
+                from,
+                to: target,
+                promotion: None,
+            };
+            if board.is_legal(mv) {
+                let mut child = board.clone();
+                child.play_unchecked(mv);
+                best = best.max(self.probe(&child).map(Neg::neg));
+            }
+        }
+        best
+    }
+}
+
+impl Table {
+    pub fn name(&self) -> String {
+        let mut name = String::from("K");
+        name.extend(
+            self.strong
+                .iter()
+                .map(|p| char::from(*p).to_ascii_uppercase()),
+        );
+        name.push_str("vK");
+        name.extend(
+            self.weak
+                .iter()
+                .map(|p| char::from(*p).to_ascii_uppercase()),
+        );
+        name
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Self, InvalidTable> {
+        let (strong, weak) = parse_name(name).ok_or(InvalidTable::Name)?;
+        let mut table = Table {
+            strong,
+            weak,
+            data: vec![],
+        };
+        let data = bytes.strip_prefix(MAGIC).ok_or(InvalidTable::Header)?;
+        let expected = table.size().div_ceil(4);
+        if data.len() != expected {
+            return Err(InvalidTable::Size {
+                expected,
+                found: data.len(),
+            });
+        }
+        table.data = data.to_vec();
+        Ok(table)
+    }
+
+    fn pawns(&self) -> usize {
+        self.strong
+            .iter()
+            .chain(&self.weak)
+            .filter(|&&p| p == Piece::Pawn)
+            .count()
+    }
+
+    fn pieces(&self) -> usize {
+        2 + self.strong.len() + self.weak.len()
+    }
+
+    /// Piece and color of each index slot: the kings, then the strong pieces, then the weak ones.
+    fn slot(&self, i: usize) -> (Piece, Color) {
+        match i {
+            0 => (Piece::King, Color::White),
+            1 => (Piece::King, Color::Black),
+            _ if i - 2 < self.strong.len() => (self.strong[i - 2], Color::White),
+            _ => (self.weak[i - 2 - self.strong.len()], Color::Black),
+        }
+    }
+
+    fn king_squares(&self) -> usize {
+        match self.pawns() {
+            0 => 16,
+            _ => 32,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.king_squares() * 64usize.pow(self.pieces() as u32 - 1) * 2
+    }
+
+    fn get(&self, idx: usize) -> Wdl {
+        match (self.data[idx / 4] >> (idx % 4 * 2)) & 3 {
+            1 => Wdl::Win,
+            2 => Wdl::Loss,
+            _ => Wdl::Draw,
+        }
+    }
+
+    fn index(&self, mut squares: [u8; TB_MAX_PIECES], strong_to_move: bool) -> usize {
+        let n = self.pieces();
+        if squares[0] & 7 >= 4 {
+            squares[..n].iter_mut().for_each(|sq| *sq ^= 7);
+        }
+        if self.pawns() == 0 && squares[0] >> 3 >= 4 {
+            squares[..n].iter_mut().for_each(|sq| *sq ^= 56);
+        }
+        let king = (squares[0] >> 3) as usize * 4 + (squares[0] & 7) as usize;
+        let idx = squares[1..n]
+            .iter()
+            .fold(king, |idx, &sq| idx * 64 + sq as usize);
+        idx * 2 + !strong_to_move as usize
+    }
+
+    fn decode(&self, mut idx: usize) -> ([u8; TB_MAX_PIECES], bool) {
+        let strong_to_move = idx.is_multiple_of(2);
+        idx /= 2;
+        let mut squares = [0; TB_MAX_PIECES];
+        for i in (1..self.pieces()).rev() {
+            squares[i] = (idx % 64) as u8;
+            idx /= 64;
+        }
+        squares[0] = (idx / 4 * 8 + idx % 4) as u8;
+        (squares, strong_to_move)
+    }
+
+    fn index_of(&self, board: &Board, strong: Color) -> usize {
+        let flip = match strong {
+            Color::White => 0,
+            Color::Black => 56,
+        };
+        let mut squares = [0; TB_MAX_PIECES];
+        squares[0] = board.king(strong) as u8 ^ flip;
+        squares[1] = board.king(!strong) as u8 ^ flip;
+        let mut i = 2;
+        for (color, pieces) in [(strong, &self.strong), (!strong, &self.weak)] {
+            // slots hold the most valuable pieces first, like `side_pieces`
+            for &piece in Piece::ALL[..5].iter().rev().filter(|p| pieces.contains(p)) {
+                for sq in board.colored_pieces(color, piece) {
+                    squares[i] = sq as u8 ^ flip;
+                    i += 1;
+                }
+            }
+        }
+        self.index(squares, board.side_to_move() == strong)
+    }
+
+    /// Builds the position at `idx`, or returns `None` if it can't occur in a game.
+    fn board(&self, idx: usize) -> Option<Board> {
+        let (squares, strong_to_move) = self.decode(idx);
+        let n = self.pieces();
+        let occupied = squares[..n].iter().fold(BitBoard::EMPTY, |bb, &sq| {
+            bb | Square::index(sq as usize).bitboard()
+        });
+        let kings = [0, 1].map(|i| Square::index(squares[i] as usize));
+        if occupied.len() as usize != n || get_king_moves(kings[0]).has(kings[1]) {
+            return None;
+        }
+
+        let mut builder = BoardBuilder::empty();
+        for (i, &sq) in squares[..n].iter().enumerate() {
+            *builder.square_mut(Square::index(sq as usize)) = Some(self.slot(i));
+        }
+        builder.side_to_move = match strong_to_move {
+            true => Color::White,
+            false => Color::Black,
+        };
+        builder.build().ok()
+    }
+
+    /// Calls `f` with the index of every position from which `mover` reaches the position given
+    /// by `squares` with a non-capturing move. Double pawn pushes also pass the move made.
+    fn unmoves(
+        &self,
+        squares: [u8; TB_MAX_PIECES],
+        mover: bool,
+        mut f: impl FnMut(usize, Option<(Square, Square)>),
+    ) {
+        let n = self.pieces();
+        let occupied = squares[..n].iter().fold(BitBoard::EMPTY, |bb, &sq| {
+            bb | Square::index(sq as usize).bitboard()
+        });
+        let mover_color = match mover {
+            true => Color::White,
+            false => Color::Black,
+        };
+
+        for i in 0..n {
+            let (piece, color) = self.slot(i);
+            if color != mover_color {
+                continue;
+            }
+            let to = Square::index(squares[i] as usize);
+            let mut double_from = None;
+            let froms = match piece {
+                Piece::King => get_king_moves(to),
+                Piece::Knight => get_knight_moves(to),
+                Piece::Bishop => get_bishop_moves(to, occupied),
+                Piece::Rook => get_rook_moves(to, occupied),
+                Piece::Queen => get_bishop_moves(to, occupied) | get_rook_moves(to, occupied),
+                Piece::Pawn => {
+                    let back = |sq: Square| match color {
+                        Color::White => sq.try_offset(0, -1),
+                        Color::Black => sq.try_offset(0, 1),
+                    };
+                    let rank = to.rank().relative_to(color);
+                    let single = back(to).filter(|_| rank > Rank::Second);
+                    let single = single.filter(|&sq| !occupied.has(sq));
+                    double_from = single
+                        .filter(|_| rank == Rank::Fourth)
+                        .and_then(back)
+                        .filter(|&sq| !occupied.has(sq));
+                    single.map_or(BitBoard::EMPTY, |sq| sq.bitboard())
+                        | double_from.map_or(BitBoard::EMPTY, |sq| sq.bitboard())
+                }
+            } & !occupied;
+
+            for from in froms {
+                let mut pred = squares;
+                pred[i] = from as u8;
+                let double_push = (double_from == Some(from)).then_some((from, to));
+                f(self.index(pred, mover), double_push);
+            }
+        }
+    }
+}
+
+impl Neg for Wdl {
+    type Output = Wdl;
+
+    fn neg(self) -> Self::Output {
+        match self {
+            Wdl::Loss => Wdl::Win,
+            Wdl::Draw => Wdl::Draw,
+            Wdl::Win => Wdl::Loss,
+        }
+    }
+}
+
+impl Display for InvalidTable {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InvalidTable::Name => write!(f, "not a table name"),
+            InvalidTable::Header => write!(f, "not a tablebase file"),
+            InvalidTable::Size { expected, found } => {
+                write!(f, "expected {expected} bytes of data, found {found}")
+            }
+            InvalidTable::MissingDependency(name) => write!(f, "table {name} is required"),
+        }
+    }
+}
+
+/// Non-king pieces of one side, most valuable first.
+fn side_pieces(board: &Board, color: Color) -> ArrayVec<Piece, 2> {
+    let mut pieces = ArrayVec::new();
+    for &piece in Piece::ALL[..5].iter().rev() {
+        for _ in board.colored_pieces(color, piece) {
+            if pieces.try_push(piece).is_err() {
+                return pieces;
+            }
+        }
+    }
+    pieces
+}
+
+fn material_name(board: &Board) -> String {
+    let table = Table {
+        strong: side_pieces(board, Color::White),
+        weak: side_pieces(board, Color::Black),
+        data: vec![],
+    };
+    table.name()
+}
+
+/// Whether the stronger side is listed first, so every material configuration has one name.
+fn canonical(strong: &[Piece], weak: &[Piece]) -> bool {
+    let key = |side: &[Piece]| {
+        (
+            side.len(),
+            side.iter().map(|&p| p as u8).collect::<Vec<_>>(),
+        )
+    };
+    key(strong) >= key(weak)
+}
+
+fn parse_name(name: &str) -> Option<(ArrayVec<Piece, 2>, ArrayVec<Piece, 2>)> {
+    let (strong, weak) = name.split_once('v')?;
+    let side = |s: &str| -> Option<ArrayVec<Piece, 2>> {
+        let mut pieces = ArrayVec::new();
+        for c in s.strip_prefix('K')?.chars() {
+            let piece = Piece::try_from(c.to_ascii_lowercase()).ok()?;
+            if piece == Piece::King {
+                return None;
+            }
+            pieces.try_push(piece).ok()?;
+        }
+        pieces.sort_by_key(|&p| core::cmp::Reverse(p as u8));
+        Some(pieces)
+    };
+    let (strong, weak) = (side(strong)?, side(weak)?);
+    let count = strong.len() + weak.len();
+    (count > 0 && count + 2 <= TB_MAX_PIECES && canonical(&strong, &weak)).then_some((strong, weak))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate(names: &[&str]) -> Tablebase {
+        let mut tablebase = Tablebase::default();
+        for name in names {
+            let table = tablebase.generate(name).unwrap();
+            tablebase.add(table);
+        }
+        tablebase
+    }
+
+    fn check(tablebase: &Tablebase, positions: &[(&str, Wdl)]) {
+        for &(fen, expected) in positions {
+            let board = Board::from_fen(fen, false).unwrap();
+            assert_eq!(tablebase.probe(&board), Some(expected), "{fen}");
+        }
+    }
+
+    #[test]
+    fn krvk() {
+        check(
+            &generate(&["KRvK"]),
+            &[
+                ("8/8/8/4k3/8/8/8/R3K3 w - - 0 1", Wdl::Win),
+                ("8/8/8/4k3/8/8/8/R3K3 b - - 0 1", Wdl::Loss),
+                // the king takes the undefended rook
+                ("8/8/8/8/8/8/1k6/R2K4 b - - 0 1", Wdl::Draw),
+                ("8/8/8/8/8/8/1k6/R2K4 w - - 0 1", Wdl::Win),
+                // stalemate
+                ("k7/1RK5/8/8/8/8/8/8 b - - 0 1", Wdl::Draw),
+                ("k7/2K5/8/8/8/8/8/R7 b - - 0 1", Wdl::Loss),
+            ],
+        );
+    }
+
+    #[test]
+    fn kpvk() {
+        check(
+            &generate(&["KQvK", "KRvK", "KBvK", "KNvK", "KPvK"]),
+            &[
+                ("7k/4P3/4K3/8/8/8/8/8 w - - 0 1", Wdl::Win),
+                ("7k/4P3/4K3/8/8/8/8/8 b - - 0 1", Wdl::Loss),
+                // zugzwang, or stalemate with black to move
+                ("4k3/4P3/4K3/8/8/8/8/8 w - - 0 1", Wdl::Win),
+                ("4k3/4P3/4K3/8/8/8/8/8 b - - 0 1", Wdl::Draw),
+                // the rule of the square
+                ("8/8/8/8/1k6/8/7P/7K w - - 0 1", Wdl::Win),
+                ("8/8/8/8/1k6/8/7P/7K b - - 0 1", Wdl::Draw),
+                // the king reaches the corner in front of the rook pawn
+                ("8/6k1/8/8/8/8/P7/K7 b - - 0 1", Wdl::Draw),
+            ],
+        );
+    }
+
+    #[test]
+    #[ignore = "takes minutes without optimizations, run with --release --include-ignored"]
+    fn krvkb() {
+        check(
+            &generate(&["KRvK", "KBvK", "KRvKB"]),
+            &[
+                ("1k6/8/8/8/8/8/8/Rb2K3 w - - 0 1", Wdl::Win),
+                // the bishop takes the rook giving check
+                ("k7/8/8/8/3b4/8/8/R3K3 b - - 0 1", Wdl::Draw),
+                ("kb6/8/1K6/8/8/8/8/R7 b - - 0 1", Wdl::Loss),
+            ],
+        );
+    }
+}
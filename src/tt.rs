@@ -1,4 +1,8 @@
-use alloc::boxed::Box;
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use alloc::vec;
+use core::fmt::Display;
+use core::ops::Deref;
+use core::ptr::NonNull;
 use core::sync::atomic::{AtomicU64, Ordering};
 
 use bytemuck::{Pod, Zeroable};
@@ -6,93 +10,445 @@ use cozy_chess::{Move, Piece, Square};
 
 use crate::Eval;
 
+/// Entries per bucket. Each entry is two words, so a bucket fills one cache line.
+const BUCKET_ENTRIES: usize = 4;
+const AGE_BITS: u32 = 6;
+const AGE_MASK: u8 = (1 << AGE_BITS) - 1;
+/// Marks an entry which has no static eval.
+const NO_EVAL: i16 = i16::MIN;
+
+const FILE_MAGIC: [u8; 8] = *b"FBHASH\0\x01";
+const FILE_HEADER_SIZE: usize = 32;
+/// Bytes written or read at a time when saving or loading the table.
+const FILE_CHUNK_SIZE: usize = 1 << 16;
+/// Largest table size in megabytes.
+pub const MAX_MB: usize = 1 << 20;
+/// Size of a transparent huge page on x86-64 and most aarch64 Linux systems.
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
 pub struct TranspositionTable {
-    table: Box<[AtomicU64]>,
-    entries: usize,
+    table: Buckets,
+    age: u8,
 }
 
-#[derive(Pod, Zeroable, Copy, Clone)]
-#[repr(C)]
+/// Zeroed bucket storage. On Linux, tables of at least a huge page are mapped directly, aligned to
+/// a huge page and marked with `MADV_HUGEPAGE`, since a large table accessed at random otherwise
+/// misses the TLB on nearly every probe. The memory isn't touched here, so the pages are faulted in
+/// by whichever threads clear the table.
+struct Buckets {
+    ptr: NonNull<Bucket>,
+    len: usize,
+    /// Layout of a heap allocation, or `None` for a mapping.
+    layout: Option<Layout>,
+    huge_pages_requested: bool,
+}
+
+// SAFETY: `Buckets` owns its allocation like a `Box<[Bucket]>` would, and buckets are atomics.
+unsafe impl Send for Buckets {}
+unsafe impl Sync for Buckets {}
+
+/// Entries are stored as `[key ^ data, data]`, so an entry torn by a concurrent write fails the
+/// key check instead of returning data belonging to another position.
+#[derive(Zeroable)]
+#[repr(C, align(64))]
+struct Bucket([[AtomicU64; 2]; BUCKET_ENTRIES]);
+
+#[derive(Copy, Clone)]
 pub struct TtSearchEntry {
-    pub lower_hash_bits: u16,
     pub mv: PackedMove,
     pub score: Eval,
     pub depth: u8,
     pub bound: Bound,
 }
 
-#[derive(Pod, Zeroable, Copy, Clone)]
-#[repr(C)]
-struct TtEvalEntry {
-    pub lower_hash_bits: u16,
-    pub eval: Eval,
-    pub _padding: u32,
+/// Unpacked entry data. `bound` is zero for entries which only hold a static eval.
+#[derive(Copy, Clone)]
+struct TtData {
+    mv: PackedMove,
+    score: Eval,
+    eval: i16,
+    depth: u8,
+    bound: Bound,
+    age: u8,
 }
 
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(transparent)]
 pub struct PackedMove(u16);
 
+impl Buckets {
+    fn new(len: usize) -> Self {
+        let size = len * size_of::<Bucket>();
+        if size >= HUGE_PAGE_SIZE {
+            if let Some((ptr, huge_pages_requested)) = map_huge_pages(size) {
+                return Buckets {
+                    ptr: ptr.cast(),
+                    len,
+                    layout: None,
+                    huge_pages_requested,
+                };
+            }
+        }
+
+        let layout = Layout::array::<Bucket>(len).unwrap();
+        let ptr = match size {
+            0 => NonNull::dangling(),
+            // SAFETY: the layout has a nonzero size
+            _ => match NonNull::new(unsafe { alloc_zeroed(layout) }) {
+                Some(ptr) => ptr.cast(),
+                None => handle_alloc_error(layout),
+            },
+        };
+        Buckets {
+            ptr,
+            len,
+            layout: Some(layout),
+            huge_pages_requested: false,
+        }
+    }
+}
+
+/// Bytes mapped for a table of `size` bytes, so that both ends of the mapping are aligned to huge
+/// pages.
+#[cfg(target_os = "linux")]
+fn mapped_size(size: usize) -> usize {
+    size.next_multiple_of(HUGE_PAGE_SIZE)
+}
+
+/// Maps zeroed memory aligned to a huge page, returning whether huge pages could be requested
+/// for it.
+#[cfg(target_os = "linux")]
+fn map_huge_pages(size: usize) -> Option<(NonNull<u8>, bool)> {
+    let size = mapped_size(size);
+    // over-allocate so an aligned range is guaranteed to fit, then unmap the excess
+    let map_size = size + HUGE_PAGE_SIZE;
+    // SAFETY: a new anonymous mapping doesn't alias any existing memory
+    let base = unsafe {
+        libc::mmap(
+            core::ptr::null_mut(),
+            map_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if base == libc::MAP_FAILED {
+        return None;
+    }
+
+    let base = base as usize;
+    let start = base.next_multiple_of(HUGE_PAGE_SIZE);
+    let end = start + size;
+    // SAFETY: both ranges are page aligned parts of the mapping which nothing refers to
+    unsafe {
+        if start > base {
+            libc::munmap(base as *mut _, start - base);
+        }
+        if base + map_size > end {
+            libc::munmap(end as *mut _, base + map_size - end);
+        }
+    }
+
+    // SAFETY: the range is the remaining mapping
+    let requested = unsafe { libc::madvise(start as *mut _, size, libc::MADV_HUGEPAGE) == 0 };
+    Some((NonNull::new(start as *mut u8)?, requested))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn map_huge_pages(_: usize) -> Option<(NonNull<u8>, bool)> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn unmap(ptr: *mut u8, size: usize) {
+    // SAFETY: the caller owns the mapping
+    unsafe {
+        libc::munmap(ptr.cast(), mapped_size(size));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmap(_: *mut u8, _: usize) {
+    unreachable!("tables are only mapped on Linux");
+}
+
+impl Deref for Buckets {
+    type Target = [Bucket];
+
+    fn deref(&self) -> &[Bucket] {
+        // SAFETY: `ptr` points to `len` initialized buckets, or is dangling with `len` zero
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Buckets {
+    fn drop(&mut self) {
+        match self.layout {
+            Some(layout) if layout.size() == 0 => {}
+            // SAFETY: `ptr` was allocated with `layout`
+            Some(layout) => unsafe { dealloc(self.ptr.as_ptr().cast(), layout) },
+            None => unmap(self.ptr.as_ptr().cast(), self.len * size_of::<Bucket>()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InvalidHashFile {
+    Header,
+    Network,
+}
+
 #[derive(Pod, Zeroable, Copy, Clone, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct Bound(u8);
 
 impl TranspositionTable {
     pub fn new(mb: usize) -> Self {
-        let entries = mb * 1024 * 1024 / 16;
+        let buckets = mb * 1024 * 1024 / size_of::<Bucket>();
         TranspositionTable {
-            table: bytemuck::zeroed_slice_box(entries * 2),
-            entries,
+            table: Buckets::new(buckets),
+            age: 0,
         }
     }
 
-    pub fn load(&self, hash: u64, _ply: usize) -> (Option<TtSearchEntry>, Option<Eval>) {
-        let search_data: TtSearchEntry =
-            bytemuck::cast(self.slot_search(hash).load(Ordering::Relaxed));
-        let eval_data: TtEvalEntry = bytemuck::cast(self.slot_eval(hash).load(Ordering::Relaxed));
+    /// Advances the age used to prefer replacing entries from previous searches.
+    pub fn new_search(&mut self) {
+        self.age = (self.age + 1) & AGE_MASK;
+    }
+
+    /// Mate and tablebase scores are stored relative to the node rather than the root, so `ply` is
+    /// needed to convert them back.
+    pub fn load(&self, hash: u64, ply: usize) -> (Option<TtSearchEntry>, Option<Eval>) {
+        let Some((_, data)) = self.find(hash) else {
+            return (None, None);
+        };
 
-        let search_data = (search_data.lower_hash_bits == hash as u16).then_some(search_data);
-        let eval_data = (eval_data.lower_hash_bits == hash as u16).then_some(eval_data.eval);
+        let search_data = (data.bound.0 != 0).then_some(TtSearchEntry {
+            mv: data.mv,
+            score: data.score.add_time(ply),
+            depth: data.depth,
+            bound: data.bound,
+        });
+        let eval_data = (data.eval != NO_EVAL).then(|| bytemuck::cast(data.eval));
 
         (search_data, eval_data)
     }
 
-    pub fn store_search(&self, hash: u64, _ply: usize, mut entry: TtSearchEntry) {
-        entry.lower_hash_bits = hash as u16;
-        entry.score = entry.score.clamp_nonmate();
-        self.slot_search(hash)
-            .store(bytemuck::cast(entry), Ordering::Relaxed);
-    }
+    pub fn store_search(&self, hash: u64, ply: usize, entry: TtSearchEntry) {
+        // the same position is always overwritten, so only other positions compete for a slot
+        let (slot, eval) = match self.find(hash) {
+            Some((slot, old)) => (slot, old.eval),
+            None => (self.replacement(hash), NO_EVAL),
+        };
 
-    pub fn store_eval(&self, hash: u64, eval: Eval) {
-        self.slot_eval(hash).store(
-            bytemuck::cast(TtEvalEntry {
-                lower_hash_bits: hash as u16,
+        Self::write(
+            slot,
+            hash,
+            TtData {
+                mv: entry.mv,
+                score: entry.score.sub_time(ply),
                 eval,
-                _padding: 0,
-            }),
-            Ordering::Relaxed,
+                depth: entry.depth,
+                bound: entry.bound,
+                age: self.age,
+            },
         );
     }
 
+    pub fn store_eval(&self, hash: u64, eval: Eval) {
+        let eval = bytemuck::cast(eval);
+        let (slot, data) = match self.find(hash) {
+            Some((slot, old)) => (slot, TtData { eval, ..old }),
+            None => (
+                self.replacement(hash),
+                TtData {
+                    mv: PackedMove(0),
+                    score: Eval::cp(0),
+                    eval,
+                    depth: 0,
+                    bound: Bound(0),
+                    age: self.age,
+                },
+            ),
+        };
+        Self::write(slot, hash, data);
+    }
+
     pub fn prefetch(&self, hash: u64) {
         #[cfg(target_arch = "x86_64")]
         unsafe {
             use core::arch::x86_64::*;
-            _mm_prefetch::<_MM_HINT_T0>(self.slot_search(hash) as *const _ as *const _);
+            _mm_prefetch::<_MM_HINT_T0>(self.bucket(hash) as *const _ as *const _);
         }
     }
 
+    /// Permille of the table written during the current search, estimated from the first thousand
+    /// entries.
+    pub fn hashfull(&self) -> u16 {
+        let buckets = self.table.len().min(1000 / BUCKET_ENTRIES);
+        let used = self.table[..buckets]
+            .iter()
+            .flat_map(|bucket| &bucket.0)
+            .filter(|[key, data]| {
+                let data = data.load(Ordering::Relaxed);
+                let written = key.load(Ordering::Relaxed) | data != 0;
+                written && TtData::unpack(data).age == self.age
+            })
+            .count();
+        (used * 1000 / (buckets * BUCKET_ENTRIES).max(1)) as u16
+    }
+
+    /// Whether transparent huge pages were requested for the table. The kernel may still back it
+    /// with normal pages.
+    pub fn huge_pages_requested(&self) -> bool {
+        self.table.huge_pages_requested
+    }
+
     pub fn raw(&self) -> &[AtomicU64] {
-        &self.table
+        // SAFETY: a bucket is a C array of atomics without padding
+        unsafe {
+            core::slice::from_raw_parts(
+                self.table.as_ptr().cast(),
+                self.table.len() * BUCKET_ENTRIES * 2,
+            )
+        }
+    }
+
+    /// Writes the table preceded by a header recording its size, its age and `network`, the
+    /// fingerprint of the network used to fill it.
+    pub fn save_to<E>(
+        &self,
+        network: u64,
+        mut write: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        header[..8].copy_from_slice(&FILE_MAGIC);
+        header[8..16].copy_from_slice(&(self.raw().len() as u64 * 8).to_le_bytes());
+        header[16..24].copy_from_slice(&network.to_le_bytes());
+        header[24] = self.age;
+        write(&header)?;
+
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+        for words in self.raw().chunks(FILE_CHUNK_SIZE / 8) {
+            let buf = &mut buf[..words.len() * 8];
+            for (bytes, word) in buf.chunks_exact_mut(8).zip(words) {
+                bytes.copy_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+            }
+            write(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a table written by [`Self::save_to`], replacing this one. The table is reallocated if
+    /// the sizes differ. Invalid headers are rejected before anything is changed, while a read
+    /// failing partway through the entries leaves the table cleared.
+    pub fn load_from<E: From<InvalidHashFile>>(
+        &mut self,
+        network: u64,
+        mut read: impl FnMut(&mut [u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        read(&mut header)?;
+        let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        let bytes = field(8);
+        let mb = bytes / (1024 * 1024);
+        if header[..8] != FILE_MAGIC
+            || bytes % (1024 * 1024) != 0
+            || !(1..=MAX_MB as u64).contains(&mb)
+        {
+            return Err(InvalidHashFile::Header.into());
+        }
+        if field(16) != network {
+            return Err(InvalidHashFile::Network.into());
+        }
+
+        if bytes != self.raw().len() as u64 * 8 {
+            *self = TranspositionTable::new(0);
+            *self = TranspositionTable::new(mb as usize);
+        }
+        self.age = header[24] & AGE_MASK;
+
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+        for words in self.raw().chunks(FILE_CHUNK_SIZE / 8) {
+            let buf = &mut buf[..words.len() * 8];
+            if let Err(e) = read(buf) {
+                for word in self.raw() {
+                    word.store(0, Ordering::Relaxed);
+                }
+                return Err(e);
+            }
+            for (bytes, word) in buf.chunks_exact(8).zip(words) {
+                word.store(
+                    u64::from_le_bytes(bytes.try_into().unwrap()),
+                    Ordering::Relaxed,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn bucket(&self, hash: u64) -> &Bucket {
+        &self.table[(hash as u128 * self.table.len() as u128 >> 64) as usize]
+    }
+
+    fn find(&self, hash: u64) -> Option<(&[AtomicU64; 2], TtData)> {
+        self.bucket(hash).0.iter().find_map(|slot| {
+            let data = slot[1].load(Ordering::Relaxed);
+            let key = slot[0].load(Ordering::Relaxed) ^ data;
+            (key == hash).then(|| (slot, TtData::unpack(data)))
+        })
+    }
+
+    /// Picks the entry to overwrite with a new position: the shallowest, with entries from
+    /// previous searches counting as shallower the older they are.
+    fn replacement(&self, hash: u64) -> &[AtomicU64; 2] {
+        self.bucket(hash)
+            .0
+            .iter()
+            .min_by_key(|slot| {
+                let data = TtData::unpack(slot[1].load(Ordering::Relaxed));
+                let relative_age = self.age.wrapping_sub(data.age) & AGE_MASK;
+                data.depth as i32 + (data.bound.0 != 0) as i32 - 8 * relative_age as i32
+            })
+            .unwrap()
+    }
+
+    fn write(slot: &[AtomicU64; 2], hash: u64, data: TtData) {
+        let data = data.pack();
+        slot[0].store(hash ^ data, Ordering::Relaxed);
+        slot[1].store(data, Ordering::Relaxed);
     }
+}
 
-    fn slot_search(&self, hash: u64) -> &AtomicU64 {
-        &self.table[(hash as u128 * self.entries as u128 >> 64) as u64 as usize * 2]
+impl Display for InvalidHashFile {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InvalidHashFile::Header => write!(f, "not a hash file"),
+            InvalidHashFile::Network => write!(f, "hash file was saved with a different network"),
+        }
     }
+}
 
-    fn slot_eval(&self, hash: u64) -> &AtomicU64 {
-        &self.table[(hash as u128 * self.entries as u128 >> 64) as u64 as usize * 2 + 1]
+impl TtData {
+    fn pack(self) -> u64 {
+        self.mv.0 as u64
+            | (bytemuck::cast::<Eval, u16>(self.score) as u64) << 16
+            | (self.eval as u16 as u64) << 32
+            | (self.depth as u64) << 48
+            | (self.bound.0 as u64) << 56
+            | (self.age as u64) << 58
+    }
+
+    fn unpack(data: u64) -> Self {
+        TtData {
+            mv: PackedMove(data as u16),
+            score: bytemuck::cast((data >> 16) as u16),
+            eval: (data >> 32) as i16,
+            depth: (data >> 48) as u8,
+            bound: Bound((data >> 56) as u8 & 3),
+            age: (data >> 58) as u8,
+        }
     }
 }
 
@@ -149,3 +505,109 @@ impl Bound {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::time::Duration;
+
+    use cozy_chess::{Board, Move};
+
+    use super::*;
+    use crate::{Limits, LocalData, Search, SharedData};
+
+    /// Searches `board` to `depth`, returning the number of moves to mate it reports.
+    fn mate_in(shared: &mut SharedData, data: &mut LocalData, board: &Board, depth: i16) -> i16 {
+        shared.prepare_for_search();
+        let mut score = None;
+        Search {
+            root: board,
+            history: Vec::new(),
+            clock: &|| Duration::ZERO,
+            info: &mut |info| score = Some(info.score),
+            curr_move: &mut |_, _| {},
+            data,
+            shared,
+            limits: Limits {
+                depth: Some(depth),
+                ..Default::default()
+            },
+        }
+        .search();
+        let score = score.unwrap();
+        score
+            .mating_in()
+            .unwrap_or_else(|| panic!("expected a mate, got {score}"))
+    }
+
+    fn board(fen: &str) -> Board {
+        Board::from_fen(fen, false).unwrap()
+    }
+
+    #[test]
+    fn scores_are_stored_relative_to_the_node() {
+        let tt = TranspositionTable::new(1);
+        let mv = PackedMove::from(Move {
+            from: Square::A1,
+            to: Square::A2,
+            promotion: None,
+        });
+        for (hash, score, expected) in [
+            (1, Eval::mating(7), Eval::mating(5)),
+            (2, Eval::mated(8), Eval::mated(6)),
+            (3, Eval::tb_win(9), Eval::tb_win(7)),
+            (4, Eval::tb_loss(10), Eval::tb_loss(8)),
+            (5, Eval::cp(250), Eval::cp(250)),
+            // the largest static evaluations must not be mistaken for tablebase scores
+            (6, Eval::clamped_cp(28_500), Eval::clamped_cp(28_500)),
+            (7, Eval::clamped_cp(-28_500), Eval::clamped_cp(-28_500)),
+        ] {
+            let entry = TtSearchEntry {
+                mv,
+                score,
+                depth: 5,
+                bound: Bound::EXACT,
+            };
+            tt.store_search(hash, 4, entry);
+            let (entry, _) = tt.load(hash, 2);
+            assert_eq!(entry.unwrap().score, expected);
+        }
+    }
+
+    #[test]
+    fn mate_in_n_is_stable_across_tt_hits() {
+        for (fen, n, depth) in [
+            ("k7/8/1K6/8/8/8/8/7R w - - 0 1", 1, 6),
+            (
+                "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5Q2/PPPP1PPP/RNB1K1NR w KQkq - 0 1",
+                1,
+                6,
+            ),
+            ("6k1/pp4p1/2p5/2bp4/8/P5Pb/1P3rrP/2BRRN1K b - - 0 1", 2, 10),
+        ] {
+            let board = board(fen);
+            let mut shared = SharedData::new(4);
+            let mut data = LocalData::new();
+            assert_eq!(mate_in(&mut shared, &mut data, &board, depth), n, "{fen}");
+            // the second search starts from the entries written by the first
+            assert_eq!(mate_in(&mut shared, &mut data, &board, depth), n, "{fen}");
+        }
+    }
+
+    #[test]
+    fn mate_in_n_is_stable_across_plies() {
+        let parent = board("6k1/pp4p1/2p5/2bp4/8/P5Pb/1P3rrP/2BRRN1K b - - 0 1");
+        let mut child = parent.clone();
+        for mv in ["g2g1", "h1g1"] {
+            child.play(mv.parse().unwrap());
+        }
+
+        let mut shared = SharedData::new(4);
+        let mut data = LocalData::new();
+        // entries written at the child's root are found two plies deep in the parent's search,
+        // and the other way around
+        assert_eq!(mate_in(&mut shared, &mut data, &child, 8), 1);
+        assert_eq!(mate_in(&mut shared, &mut data, &parent, 10), 2);
+        assert_eq!(mate_in(&mut shared, &mut data, &child, 8), 1);
+    }
+}